@@ -1,5 +1,6 @@
 use crate::unit;
 use crate::utils;
+use na::Vector2;
 use serde::{Deserialize, Serialize};
 use typename::TypeName;
 use utils::Id;
@@ -9,7 +10,23 @@ pub struct BotDef {
     pub id: Id<BotDef>,
     pub file_path: String,
     pub radius: f32,
+    ///Resistance to being pushed by other units in collisions, structures are never pushed
+    pub mass: f32,
     pub max_life: i32,
+    ///See ModDef::damage_multipliers
+    pub armor_class: String,
+    ///m, blocked by the terrain
+    pub sight_radius: f32,
+    ///m, not blocked by the terrain
+    pub radar_radius: f32,
+    ///m, allied kbots inside are hidden from enemy radars
+    pub jammer_radius: f32,
+    ///Can be ordered to turn invisible to enemies
+    pub cloak: bool,
+    ///energy/frame, while cloaked
+    pub cloak_upkeep: f32,
+    ///m, enemy kbots closer than this reveal it
+    pub decloak_radius: f32,
     //Movement
     ///rad/frame²
     pub turn_accel: f32,
@@ -27,6 +44,186 @@ pub struct BotDef {
     pub build_dist: f32,
     ///metal
     pub metal_cost: i32,
+    ///energy
+    pub energy_cost: i32,
+    ///energy/frame
+    pub energy_production: f32,
+    ///energy/frame
+    pub energy_upkeep: f32,
+    ///metal, added to the storage capacity of the owner
+    pub metal_storage: f32,
+    ///energy, added to the storage capacity of the owner
+    pub energy_storage: f32,
+    ///Mines the metal spots under it once completed
+    pub extractor: bool,
+    pub weapons: Vec<WeaponDef>,
+
+    ///Never moves once built
+    pub is_structure: bool,
+    ///Builds the units of its FactoryQueue in place, see ModDef::con_map
+    pub is_factory: bool,
+    ///m, size on the ground along x and y
+    pub footprint: Vector2<f32>,
+    ///Where it can be built and move
+    pub movement_class: MovementClass,
 
     pub part_tree: unit::PartTree,
 }
+
+impl BotDef {
+    ///Range of the weapon reaching the farthest
+    pub fn max_weapon_range(&self) -> Option<f32> {
+        self.weapons
+            .iter()
+            .map(|weapon| weapon.range)
+            .fold(None, |max, range| Some(max.unwrap_or(range).max(range)))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MovementClass {
+    ///rad, steepest ground it can be built on or move on
+    pub max_slope: f32,
+    ///m, 0 allows dry land
+    pub min_water_depth: f32,
+    ///m
+    pub max_water_depth: f32,
+    ///Multiplies max_speed on ground as steep as max_slope, less on gentler ground
+    pub slope_speed_modifier: f32,
+    ///Floats on the water surface instead of walking on the bottom
+    pub hover: bool,
+}
+
+impl MovementClass {
+    pub fn kbot() -> Self {
+        MovementClass {
+            max_slope: 0.8,
+            min_water_depth: 0.0,
+            max_water_depth: 5.0,
+            slope_speed_modifier: 0.7,
+            hover: false,
+        }
+    }
+
+    pub fn tank() -> Self {
+        MovementClass {
+            max_slope: 0.5,
+            min_water_depth: 0.0,
+            max_water_depth: 3.0,
+            slope_speed_modifier: 0.5,
+            hover: false,
+        }
+    }
+
+    pub fn hover() -> Self {
+        MovementClass {
+            max_slope: 0.4,
+            min_water_depth: 0.0,
+            max_water_depth: 10000.0,
+            slope_speed_modifier: 0.6,
+            hover: true,
+        }
+    }
+
+    pub fn amphibious() -> Self {
+        MovementClass {
+            max_slope: 0.6,
+            min_water_depth: 0.0,
+            max_water_depth: 10000.0,
+            slope_speed_modifier: 0.6,
+            hover: false,
+        }
+    }
+
+    ///Movement classes with the same key can cross the same places
+    pub fn passability_key(&self) -> (u32, u32, u32, bool) {
+        (
+            self.max_slope.to_bits(),
+            self.min_water_depth.to_bits(),
+            self.max_water_depth.to_bits(),
+            self.hover,
+        )
+    }
+
+    ///Whether it can stand on ground of this slope (rad) under this water depth (m)
+    pub fn can_cross(&self, slope: f32, water_depth: f32) -> bool {
+        let floating = self.hover && water_depth > 0.0;
+        water_depth >= self.min_water_depth
+            && water_depth <= self.max_water_depth
+            && (floating || slope <= self.max_slope)
+    }
+
+    ///Multiplier of max_speed on ground of this slope (rad)
+    pub fn speed_modifier(&self, slope: f32) -> f32 {
+        1.0 + (self.slope_speed_modifier - 1.0) * (slope / self.max_slope).min(1.0)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct WeaponDef {
+    ///m
+    pub range: f32,
+    ///hp
+    pub damage: i32,
+    ///See ModDef::damage_multipliers
+    pub damage_class: String,
+    ///frame
+    pub reload_frame_count: i32,
+    ///m/frame
+    pub muzzle_speed: f32,
+    ///m/frame²
+    pub gravity: f32,
+    ///m
+    pub projectile_radius: f32,
+    ///m
+    pub aoe_radius: f32,
+    ///Ratio of the damage dealt at the edge of the aoe
+    pub edge_damage: f32,
+    ///Part of the part_tree aiming with this weapon
+    pub joint: unit::Joint,
+    pub targeting: TargetingPolicy,
+}
+
+impl WeaponDef {
+    pub fn new() -> Self {
+        WeaponDef {
+            range: 6.0,
+            damage: 10,
+            damage_class: "default".to_owned(),
+            reload_frame_count: 3,
+            muzzle_speed: 2.0,
+            gravity: 0.08,
+            projectile_radius: 0.25,
+            aoe_radius: 0.5,
+            edge_damage: 0.5,
+            joint: unit::Joint::AimWeapon0,
+            targeting: TargetingPolicy::Closest,
+        }
+    }
+
+    ///Twice the time needed to reach max range in straight line
+    pub fn life_frame_count(&self) -> i32 {
+        (self.range / self.muzzle_speed * 2.0).ceil() as i32
+    }
+}
+
+///How a weapon picks its target among the ennemies in range
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum TargetingPolicy {
+    Closest,
+    LowestLife,
+    HighestMetalCost,
+    ///Stick to the previous target while it stays in range, otherwise the closest
+    KeepTarget,
+}
+
+impl TargetingPolicy {
+    pub fn next(&self) -> Self {
+        match self {
+            TargetingPolicy::Closest => TargetingPolicy::LowestLife,
+            TargetingPolicy::LowestLife => TargetingPolicy::HighestMetalCost,
+            TargetingPolicy::HighestMetalCost => TargetingPolicy::KeepTarget,
+            TargetingPolicy::KeepTarget => TargetingPolicy::Closest,
+        }
+    }
+}
@@ -49,7 +49,11 @@ pub struct State {
 
     pub selected: FnvHashSet<Id<KBot>>,
     pub under_mouse: Option<Id<KBot>>,
+    pub feature_under_mouse: Option<Id<Feature>>,
     pub uitool: UiTool,
+    ///World positions of the current right drag
+    pub move_line: Vec<Vector3<f32>>,
+    pub keep_formation: bool,
 
     pub start_time: Instant,
     pub last_frame: Instant,
@@ -90,7 +94,10 @@ impl State {
 
             selected: FnvHashSet::default(),
             under_mouse: None,
+            feature_under_mouse: None,
             uitool: UiTool::None,
+            move_line: Vec::new(),
+            keep_formation: false,
 
             players: FnvHashMap::default(),
             my_player_id: None,
@@ -106,6 +113,15 @@ impl State {
         let time_between = self.frame_zero_time_received.elapsed();
         log::trace!("receive: NewFrame after {:?}", time_between);
         self.frame_zero_time_received = Instant::now();
+        //Only show what the team of the player knows
+        let my_team = self
+            .my_player_id
+            .and_then(|id| frame.players.get(&id))
+            .map(|player| player.team);
+        let frame = match my_team {
+            Some(team) => crate::frame_server::visibility::filter_frame(frame, team),
+            None => frame,
+        };
         self.frame_minus_one = std::mem::replace(&mut self.frame_zero, frame);
 
         let sec = self.frame_zero.number as f32 / 10.0;
@@ -206,8 +222,14 @@ impl State {
                             if let Some(kbot_m) = kbot_m_opt {
                                 client_kbot0.dir = kbot_0.dir * i0 + kbot_m.dir * im;
                                 client_kbot0.up = kbot_0.up * i0 + kbot_m.up * im;
-                                client_kbot0.weapon0_dir =
-                                    kbot_0.weapon0_dir * i0 + kbot_m.weapon0_dir * im;
+                                client_kbot0.weapon_dirs.clear();
+                                client_kbot0.weapon_dirs.extend(
+                                    kbot_0
+                                        .weapons
+                                        .iter()
+                                        .zip(kbot_m.weapons.iter())
+                                        .map(|(w0, wm)| w0.dir * i0 + wm.dir * im),
+                                );
                                 client_kbot0.wheel0_angle =
                                     kbot_0.wheel0_angle * i0 + kbot_m.wheel0_angle * im;
                             }
@@ -368,5 +368,14 @@ impl State {
             heightmap_gpu.phy.width as u32,
             heightmap_gpu.phy.height as u32,
         );
+
+        let json_path = format!("{}/data.json", path);
+        if let Ok(file) = File::open(&json_path) {
+            let buf_r = std::io::BufReader::new(file);
+            match serde_json::from_reader(buf_r) {
+                Ok(data) => heightmap_gpu.phy.data = data,
+                Err(e) => log::error!("Can't read {}: {}", json_path, e),
+            }
+        }
     }
 }
@@ -3,6 +3,10 @@ use crate::*;
 use unit_part_gpu::*;
 
 use super::uitool::UiTool;
+
+///px, half size of the icon of a radar contact
+const RADAR_CONTACT_ICON_SIZE: f32 = 6.0;
+
 impl App {
     pub fn clear_gpu_instance_and_game_state(&mut self) {
         self.game_state.players.clear();
@@ -35,7 +39,8 @@ impl App {
         highlight_factor: f32,
         team: f32,
         con_completed: f32,
-        weapon0_dir: Vector3<f32>,
+        weapons: &[botdef::WeaponDef],
+        weapon_dirs: &[Vector3<f32>],
         wheel0_angle: f32,
     ) {
         for c in part_tree.children.iter() {
@@ -44,12 +49,22 @@ impl App {
 
                 let combined = match &c.joint {
                     unit::Joint::Fix => root_trans * c.parent_to_self,
-                    unit::Joint::AimWeapon0 => {
+                    unit::Joint::AimWeapon0
+                    | unit::Joint::AimWeapon1
+                    | unit::Joint::AimWeapon2
+                    | unit::Joint::AimWeapon3 => {
                         let comb = root_trans * c.parent_to_self;
+                        //Aim with the weapon slot bound to this joint, if any
+                        let weapon_dir = weapons
+                            .iter()
+                            .position(|weapon| weapon.joint == c.joint)
+                            .and_then(|index| weapon_dirs.get(index))
+                            .copied()
+                            .unwrap_or(Vector3::new(comb[0], comb[1], comb[2]));
 
                         utils::face_towards_dir(
                             &Vector3::new(comb[12], comb[13], comb[14]),
-                            &weapon0_dir,
+                            &weapon_dir,
                             &Vector3::new(0.0, 0.0, 1.0),
                         )
                     }
@@ -106,7 +121,8 @@ impl App {
                     highlight_factor,
                     team,
                     con_completed,
-                    weapon0_dir,
+                    weapons,
+                    weapon_dirs,
                     wheel0_angle,
                 );
             } else {
@@ -117,7 +133,8 @@ impl App {
                     highlight_factor,
                     team,
                     con_completed,
-                    weapon0_dir,
+                    weapons,
+                    weapon_dirs,
                     wheel0_angle,
                 );
             }
@@ -148,6 +165,8 @@ impl App {
 
                 let t = self.game_state.start_time.elapsed().as_secs_f32();
                 if self.main_menu == MainMode::UnitEditor {
+                    let weapon_dir =
+                        Vector3::new(f32::cos(t), f32::sin(t), f32::sin(t / 5.0) * 0.1).normalize();
                     Self::visit_part_tree(
                         &self.unit_editor.botdef.part_tree,
                         &identity,
@@ -155,7 +174,8 @@ impl App {
                         0.0,
                         0.0,
                         1.0,
-                        Vector3::new(f32::cos(t), f32::sin(t), f32::sin(t / 5.0) * 0.1).normalize(),
+                        &self.unit_editor.botdef.weapons,
+                        &vec![weapon_dir; self.unit_editor.botdef.weapons.len()],
                         (t * 2.0),
                     );
                 }
@@ -191,13 +211,52 @@ impl App {
                                 highlight_factor,
                                 team as f32,
                                 mobile.con_completed,
-                                client_kbot.weapon0_dir,
+                                &botdef.weapons,
+                                &client_kbot.weapon_dirs,
                                 client_kbot.wheel0_angle,
                             );
                         }
                     }
                 }
 
+                //Feature
+                {
+                    for feature in self.game_state.frame_zero.features.values() {
+                        let distance_to_camera =
+                            (feature.position - self.game_state.position_smooth).magnitude();
+                        if distance_to_camera < unit_icon_distance {
+                            let mat = utils::face_towards_dir(
+                                &feature.position.coords,
+                                &feature.dir.normalize(),
+                                &feature.up,
+                            );
+                            let highlight_factor =
+                                if self.game_state.feature_under_mouse == Some(feature.id) {
+                                    2.0
+                                } else {
+                                    0.0
+                                };
+
+                            if let Some(botdef) =
+                                self.game_state.frame_zero.bot_defs.get(&feature.botdef_id)
+                            {
+                                //Team 99 has the neutral color
+                                Self::visit_part_tree(
+                                    &botdef.part_tree,
+                                    &mat,
+                                    &mut self.unit_part_gpu,
+                                    highlight_factor,
+                                    99.0,
+                                    1.0,
+                                    &botdef.weapons,
+                                    &vec![feature.dir; botdef.weapons.len()],
+                                    0.0,
+                                );
+                            }
+                        }
+                    }
+                }
+
                 for model_gpu in self.unit_part_gpu.states.iter_mut() {
                     match model_gpu {
                         ModelGpuState::Ready(model_gpu) => {
@@ -388,6 +447,24 @@ impl App {
                 let team = if is_selected { -1.0 } else { kbot.team as f32 };
                 self.vertex_attr_buffer_f32.push(team);
             }
+            //Radar contacts are always icons, at any distance
+            for contact in self.game_state.frame_zero.radar_contacts.iter() {
+                let screen_pos = view_proj * contact.position.to_homogeneous();
+                if screen_pos.z > 0.0
+                    && screen_pos.x > -screen_pos.w
+                    && screen_pos.x < screen_pos.w
+                    && screen_pos.y > -screen_pos.w
+                    && screen_pos.y < screen_pos.w
+                {
+                    self.vertex_attr_buffer_f32
+                        .push(screen_pos.x / screen_pos.w);
+                    self.vertex_attr_buffer_f32
+                        .push(screen_pos.y / screen_pos.w);
+                    self.vertex_attr_buffer_f32.push(RADAR_CONTACT_ICON_SIZE);
+                    //Team -2 is drawn as a radar contact
+                    self.vertex_attr_buffer_f32.push(-2.0);
+                }
+            }
             self.unit_icon
                 .update_instance(&self.vertex_attr_buffer_f32[..], &self.gpu.device);
 
@@ -453,84 +530,109 @@ impl App {
                     .key_pressed
                     .contains(&winit::event::VirtualKeyCode::LShift);
                 {
+                    fn add_line(
+                        view_proj: &Matrix4<f32>,
+                        buffer: &mut Vec<f32>,
+                        start: &Point3<f32>,
+                        end: &Point3<f32>,
+                        type_: f32,
+                        count: &mut i32,
+                    ) {
+                        let min = view_proj * start.to_homogeneous();
+                        let max = view_proj * end.to_homogeneous();
+                        if (min.z > 0.0
+                            && min.x > -min.w
+                            && min.x < min.w
+                            && min.y > -min.w
+                            && min.y < min.w)
+                            || (max.z > 0.0
+                                && max.x > -max.w
+                                && max.x < max.w
+                                && max.y > -max.w
+                                && max.y < max.w)
+                        {
+                            *count += 1;
+                            buffer.push(min.x / min.w);
+                            buffer.push(min.y / min.w);
+                            buffer.push(max.x / max.w);
+                            buffer.push(max.y / max.w);
+                            //0.0 is move line
+                            //1.0 is build line
+                            buffer.push(type_);
+                            buffer.push(0.0);
+                        }
+                    }
+
                     for (kbot, client_kbot) in self.game_state.kbots.iter() {
                         if see_all_order || self.game_state.selected.contains(&kbot.id) {
-                            fn add_line(
-                                view_proj: &Matrix4<f32>,
-                                buffer: &mut Vec<f32>,
-                                start: &Point3<f32>,
-                                end: &Point3<f32>,
-                                type_: f32,
-                                count: &mut i32,
-                            ) {
-                                let min = view_proj * start.to_homogeneous();
-                                let max = view_proj * end.to_homogeneous();
-                                if (min.z > 0.0
-                                    && min.x > -min.w
-                                    && min.x < min.w
-                                    && min.y > -min.w
-                                    && min.y < min.w)
-                                    || (max.z > 0.0
-                                        && max.x > -max.w
-                                        && max.x < max.w
-                                        && max.y > -max.w
-                                        && max.y < max.w)
-                                {
-                                    *count += 1;
-                                    buffer.push(min.x / min.w);
-                                    buffer.push(min.y / min.w);
-                                    buffer.push(max.x / max.w);
-                                    buffer.push(max.y / max.w);
-                                    //0.0 is move line
-                                    //1.0 is build line
-                                    buffer.push(type_);
-                                    buffer.push(0.0);
+                            //Chain the lines of every queued command
+                            let mut from = client_kbot.position;
+                            for command in kbot.commands.iter() {
+                                let (to, type_) = match command {
+                                    mobile::Command::Move { target, .. } => (Some(*target), 0.0),
+                                    mobile::Command::Build(id_builded) => (
+                                        self.game_state
+                                            .frame_zero
+                                            .kbots
+                                            .get(id_builded)
+                                            .map(|e| e.position),
+                                        1.0,
+                                    ),
+                                    mobile::Command::Repair(id_builded) => (
+                                        self.game_state
+                                            .frame_zero
+                                            .kbots
+                                            .get(id_builded)
+                                            .map(|e| e.position),
+                                        2.0,
+                                    ),
+                                    mobile::Command::Reclaim(mobile::Reclaimable::KBot(
+                                        id_reclaimed,
+                                    )) => (
+                                        self.game_state
+                                            .frame_zero
+                                            .kbots
+                                            .get(id_reclaimed)
+                                            .map(|e| e.position),
+                                        2.0,
+                                    ),
+                                    mobile::Command::Reclaim(mobile::Reclaimable::Feature(
+                                        id_reclaimed,
+                                    )) => (
+                                        self.game_state
+                                            .frame_zero
+                                            .features
+                                            .get(id_reclaimed)
+                                            .map(|e| e.position),
+                                        2.0,
+                                    ),
+                                    _ => (None, 0.0),
+                                };
+                                if let Some(to) = to {
+                                    add_line(
+                                        view_proj,
+                                        &mut self.vertex_attr_buffer_f32,
+                                        &from,
+                                        &to,
+                                        type_,
+                                        &mut count,
+                                    );
+                                    from = to;
                                 }
                             }
-
-                            if let Some(target) = kbot.move_target {
-                                add_line(
-                                    view_proj,
-                                    &mut self.vertex_attr_buffer_f32,
-                                    &client_kbot.position,
-                                    &target,
-                                    0.0,
-                                    &mut count,
-                                );
-                            }
-                            match kbot.current_command {
-                                mobile::Command::Build(id_builded) => {
-                                    for target_kbot in
-                                        self.game_state.frame_zero.kbots.get(&id_builded)
-                                    {
-                                        add_line(
-                                            view_proj,
-                                            &mut self.vertex_attr_buffer_f32,
-                                            &client_kbot.position,
-                                            &target_kbot.position,
-                                            1.0,
-                                            &mut count,
-                                        );
-                                    }
-                                }
-                                mobile::Command::Repair(id_builded) => {
-                                    for target_kbot in
-                                        self.game_state.frame_zero.kbots.get(&id_builded)
-                                    {
-                                        add_line(
-                                            view_proj,
-                                            &mut self.vertex_attr_buffer_f32,
-                                            &client_kbot.position,
-                                            &target_kbot.position,
-                                            2.0,
-                                            &mut count,
-                                        );
-                                    }
-                                }
-                                _ => {}
-                            }
                         }
                     }
+                    //Right drag of a move order
+                    for segment in self.game_state.move_line.windows(2) {
+                        add_line(
+                            view_proj,
+                            &mut self.vertex_attr_buffer_f32,
+                            &Point3::from(segment[0]),
+                            &Point3::from(segment[1]),
+                            0.0,
+                            &mut count,
+                        );
+                    }
                     for i in (0..self.vertex_attr_buffer_f32.len()).step_by(6) {
                         self.vertex_attr_buffer_f32[i + 5] = count as f32;
                     }
@@ -29,6 +29,8 @@ impl App {
                     Self::load_botdef_on_disk("src/asset/botdef/unit_example.json").unwrap();
                 let building_example =
                     Self::load_botdef_on_disk("src/asset/botdef/building_example.json").unwrap();
+                let factory_example =
+                    Self::load_botdef_on_disk("src/asset/botdef/factory_example.json").unwrap();
 
                 for i in (100..300).step_by(4) {
                     for j in (100..500).step_by(4) {
@@ -63,13 +65,18 @@ impl App {
                     .players
                     .insert(player_ennemy.id, player_ennemy);
 
+                let mut con_map = FnvHashMap::default();
+                con_map.insert(factory_example.id, vec![tank_example.id]);
+
                 let mut bot_defs = FnvHashMap::default();
                 bot_defs.insert(tank_example.id, tank_example);
                 bot_defs.insert(building_example.id, building_example);
+                bot_defs.insert(factory_example.id, factory_example);
 
                 let mut moddef = crate::moddef::ModDef {
                     units_id: bot_defs.keys().copied().collect(),
-                    con_map: FnvHashMap::default(),
+                    con_map,
+                    damage_multipliers: FnvHashMap::default(),
                 };
 
                 let replacer = FrameEventFromPlayer::ReplaceFrame(frame::Frame {
@@ -77,6 +84,9 @@ impl App {
                     players: self.game_state.players.clone(),
                     moddef,
                     kbots,
+                    features: FnvHashMap::default(),
+                    visibility: FnvHashMap::default(),
+                    radar_contacts: Vec::new(),
                     kbots_dead: HashSet::default(),
                     kinematic_projectiles_dead: Vec::new(),
                     kinematic_projectiles_birth: Vec::new(),
@@ -169,8 +179,25 @@ impl App {
                         }
                     }
                     self.game_state.under_mouse = closest;
+
+                    //Wrecks are only picked in 3d, and when no kbot is under the cursor
+                    self.game_state.feature_under_mouse = if closest.is_none() {
+                        let mut closest = None;
+                        let mut distance = 999999999.0_f32;
+                        for feature in self.game_state.frame_zero.features.values() {
+                            let dist = (feature.position.coords - mpos).magnitude_squared();
+                            if dist < 1.0 && dist < distance {
+                                closest = Some(feature.id);
+                                distance = dist;
+                            }
+                        }
+                        closest
+                    } else {
+                        None
+                    };
                 } else {
                     self.game_state.under_mouse = None;
+                    self.game_state.feature_under_mouse = None;
                 }
             }
 
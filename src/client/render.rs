@@ -13,6 +13,11 @@ use unit_part_gpu::UnitPartGpu;
 use utils::time;
 use wgpu::{BufferMapAsyncResult, Extent3d};
 
+///m, distance between two points of a right drag
+const MOVE_LINE_STEP: f32 = 1.0;
+///m, a shorter right drag is a click
+const MIN_MOVE_LINE_LENGTH: f32 = 4.0;
+
 impl App {
     pub fn render(&mut self) {
         if self.frame_count == 1 {
@@ -230,6 +235,11 @@ impl App {
             self.game_state.position_smooth.x,
             self.game_state.position_smooth.y,
         );
+        self.water_gpu.update_uniform(
+            &self.gpu.device,
+            &mut encoder_render,
+            self.heightmap_gpu.phy.data.water_level,
+        );
 
         //Imgui
         let start = Instant::now();
@@ -387,21 +397,90 @@ impl App {
                         }
                     }
                     MainMode::Play => {
+                        let mut share = None;
                         if let Some(me) = self.game_state.my_player() {
+                            let allies: Vec<_> = self
+                                .game_state
+                                .players
+                                .values()
+                                .filter(|p| p.team == me.team && p.id != me.id)
+                                .collect();
                             let resource_window = imgui::Window::new(im_str!("Resources"));
                             resource_window
                                 .size([400.0, 120.0], imgui::Condition::FirstUseEver)
                                 .position([500.0, 3.0], imgui::Condition::FirstUseEver)
                                 .collapsed(false, imgui::Condition::FirstUseEver)
                                 .build(&ui, || {
-                                    ui.text(im_str!("metal: {:.1}", me.metal));
-                                    ProgressBar::new((me.metal / 500.0) as f32).build(&ui);
-                                    ui.text(im_str!("energy: {:.1}", me.energy));
-                                    ProgressBar::new((me.energy / 500.0) as f32).build(&ui);
+                                    ui.text(im_str!(
+                                        "metal: {:.1} (+{:.2} -{:.2} /frame)",
+                                        me.metal,
+                                        me.metal_income,
+                                        me.metal_expense
+                                    ));
+                                    ProgressBar::new((me.metal / me.metal_storage) as f32)
+                                        .build(&ui);
+                                    ui.text(im_str!(
+                                        "energy: {:.1} (+{:.2} -{:.2} /frame)",
+                                        me.energy,
+                                        me.energy_income,
+                                        me.energy_expense
+                                    ));
+                                    ProgressBar::new((me.energy / me.energy_storage) as f32)
+                                        .build(&ui);
+
+                                    for ally in allies.iter() {
+                                        ui.text(im_str!("ally {}", ally.id.value));
+                                        ui.same_line(0.0);
+                                        if ui.small_button(
+                                            im_str!("give 100 metal##{}", ally.id.value).as_ref(),
+                                        ) {
+                                            share = Some((ally.id, 100.0, 0.0));
+                                        }
+                                        ui.same_line(0.0);
+                                        if ui.small_button(
+                                            im_str!("give 100 energy##{}", ally.id.value).as_ref(),
+                                        ) {
+                                            share = Some((ally.id, 0.0, 100.0));
+                                        }
+                                    }
                                 });
                         }
 
+                        if let (Some((to, metal, energy)), Some(id)) =
+                            (share, self.game_state.my_player_id)
+                        {
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(
+                                    FrameEventFromPlayer::ShareResources {
+                                        id,
+                                        to,
+                                        metal,
+                                        energy,
+                                    },
+                                ),
+                            );
+                        }
+
                         let mut uitool = self.game_state.uitool;
+                        let mut keep_formation = self.game_state.keep_formation;
+                        let mut fire_state = None;
+                        let mut cloak = None;
+                        let mut cancel = false;
+                        let mut factory_queue_order = None;
+                        let mut factory_repeat = None;
+                        let frame_zero = &self.game_state.frame_zero;
+                        let factory = self
+                            .game_state
+                            .selected
+                            .iter()
+                            .filter_map(|id| frame_zero.kbots.get(id))
+                            .find(|kbot| {
+                                frame_zero
+                                    .bot_defs
+                                    .get(&kbot.botdef_id)
+                                    .map_or(false, |botdef| botdef.is_factory)
+                            });
+                        let con_map = &self.game_state.frame_zero.moddef.con_map;
                         let can_be_built: Vec<_> = self
                             .game_state
                             .frame_zero
@@ -429,8 +508,152 @@ impl App {
                                 if ui.small_button(im_str!("Repair")) {
                                     uitool = UiTool::Repair;
                                 }
+
+                                if ui.small_button(im_str!("Attack")) {
+                                    uitool = UiTool::Attack;
+                                }
+
+                                if ui.small_button(im_str!("Guard")) {
+                                    uitool = UiTool::Guard;
+                                }
+
+                                if ui.small_button(im_str!("Reclaim")) {
+                                    uitool = UiTool::Reclaim;
+                                }
+
+                                if ui.small_button(im_str!("Cancel construction")) {
+                                    cancel = true;
+                                }
+
+                                ui.checkbox(im_str!("keep formation"), &mut keep_formation);
+
+                                if let Some(factory) = factory {
+                                    ui.separator();
+                                    let units = con_map
+                                        .get(&factory.botdef_id)
+                                        .cloned()
+                                        .unwrap_or_default();
+                                    for unit in units.iter() {
+                                        let queued: i32 = factory
+                                            .factory_queue
+                                            .entries
+                                            .iter()
+                                            .filter(|entry| entry.botdef_id == *unit)
+                                            .map(|entry| entry.count)
+                                            .sum();
+                                        let file_path = can_be_built
+                                            .get(unit)
+                                            .map(|botdef| botdef.file_path.clone())
+                                            .unwrap_or_default();
+                                        ui.text(im_str!("{:?} x{}", file_path, queued));
+                                        for count in [1, 5, -1].iter() {
+                                            ui.same_line(0.0);
+                                            if ui.small_button(
+                                                im_str!("{:+}##{}", count, unit.value).as_ref(),
+                                            ) {
+                                                factory_queue_order = Some((*unit, *count));
+                                            }
+                                        }
+                                    }
+
+                                    let mut repeat = factory.factory_queue.repeat;
+                                    if ui.checkbox(im_str!("repeat"), &mut repeat) {
+                                        factory_repeat = Some(repeat);
+                                    }
+
+                                    if ui.small_button(im_str!("Rally point")) {
+                                        uitool = UiTool::Rally;
+                                    }
+                                }
+
+                                ui.separator();
+                                if ui.small_button(im_str!("Hold fire")) {
+                                    fire_state = Some(mobile::FireState::HoldFire);
+                                }
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Return fire")) {
+                                    fire_state = Some(mobile::FireState::ReturnFire);
+                                }
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Fire at will")) {
+                                    fire_state = Some(mobile::FireState::FireAtWill);
+                                }
+
+                                if ui.small_button(im_str!("Cloak")) {
+                                    cloak = Some(true);
+                                }
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Decloak")) {
+                                    cloak = Some(false);
+                                }
                             });
 
+                        if let (Some((botdef_id, count)), Some(id)) =
+                            (factory_queue_order, self.game_state.my_player_id)
+                        {
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(
+                                    FrameEventFromPlayer::FactoryQueueOrder {
+                                        id,
+                                        selected: self.game_state.selected.clone(),
+                                        botdef_id,
+                                        count,
+                                    },
+                                ),
+                            );
+                        }
+
+                        if let (Some(repeat), Some(id)) =
+                            (factory_repeat, self.game_state.my_player_id)
+                        {
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(
+                                    FrameEventFromPlayer::FactoryRepeatOrder {
+                                        id,
+                                        selected: self.game_state.selected.clone(),
+                                        repeat,
+                                    },
+                                ),
+                            );
+                        }
+
+                        if let (true, Some(id)) = (cancel, self.game_state.my_player_id) {
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(
+                                    FrameEventFromPlayer::CancelOrder {
+                                        id,
+                                        selected: self.game_state.selected.clone(),
+                                    },
+                                ),
+                            );
+                        }
+
+                        if let (Some(fire_state), Some(id)) =
+                            (fire_state, self.game_state.my_player_id)
+                        {
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(
+                                    FrameEventFromPlayer::FireStateOrder {
+                                        id,
+                                        selected: self.game_state.selected.clone(),
+                                        fire_state,
+                                    },
+                                ),
+                            );
+                        }
+
+                        if let (Some(cloak), Some(id)) = (cloak, self.game_state.my_player_id) {
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(FrameEventFromPlayer::CloakOrder {
+                                    id,
+                                    selected: self.game_state.selected.clone(),
+                                    cloak,
+                                }),
+                            );
+                        }
+
+                        self.game_state.keep_formation = keep_formation;
+
                         if self.game_state.uitool != uitool {
                             log::debug!(
                                 "UiTool state from {:?} to {:?}",
@@ -840,21 +1063,29 @@ impl App {
             self.game_state.my_player_id,
             self.game_state.mouse_world_pos,
         ) {
+            let queue = self
+                .input_state
+                .key_pressed
+                .contains(&winit::event::VirtualKeyCode::LShift);
             let orders = match self.game_state.uitool {
-                UiTool::Move | UiTool::None => vec![FrameEventFromPlayer::MoveOrder {
-                    id,
-                    selected: self.game_state.selected.clone(),
-                    mouse_world_pos,
-                }],
+                //Sent on release, see below
+                UiTool::Move | UiTool::None => {
+                    self.game_state.move_line = vec![mouse_world_pos];
+                    vec![]
+                }
 
                 UiTool::Spawn(id_to_con) => {
-                    self.game_state.uitool = UiTool::None;
+                    //Keep the tool while queuing to lay out several constructions
+                    if !queue {
+                        self.game_state.uitool = UiTool::None;
+                    }
 
                     vec![FrameEventFromPlayer::ConOrder {
                         id,
                         selected: self.game_state.selected.clone(),
                         mouse_world_pos,
                         botdef_id: id_to_con,
+                        queue,
                     }]
                 }
 
@@ -866,6 +1097,71 @@ impl App {
                             id,
                             selected: self.game_state.selected.clone(),
                             to_repair: under,
+                            queue,
+                        }]
+                    } else {
+                        vec![]
+                    }
+                }
+
+                UiTool::Attack => {
+                    self.game_state.uitool = UiTool::None;
+
+                    if let Some(under) = self.game_state.under_mouse {
+                        vec![FrameEventFromPlayer::AttackOrder {
+                            id,
+                            selected: self.game_state.selected.clone(),
+                            target: under,
+                            queue,
+                        }]
+                    } else {
+                        vec![]
+                    }
+                }
+
+                UiTool::Guard => {
+                    self.game_state.uitool = UiTool::None;
+
+                    if let Some(under) = self.game_state.under_mouse {
+                        vec![FrameEventFromPlayer::GuardOrder {
+                            id,
+                            selected: self.game_state.selected.clone(),
+                            ward: under,
+                            queue,
+                        }]
+                    } else {
+                        vec![]
+                    }
+                }
+
+                UiTool::Rally => {
+                    self.game_state.uitool = UiTool::None;
+
+                    vec![FrameEventFromPlayer::RallyPointOrder {
+                        id,
+                        selected: self.game_state.selected.clone(),
+                        mouse_world_pos,
+                    }]
+                }
+
+                UiTool::Reclaim => {
+                    self.game_state.uitool = UiTool::None;
+
+                    let target = match (
+                        self.game_state.under_mouse,
+                        self.game_state.feature_under_mouse,
+                    ) {
+                        (Some(under), _) => Some(mobile::Reclaimable::KBot(under)),
+                        (None, Some(under)) => Some(mobile::Reclaimable::Feature(under)),
+                        (None, None) => None,
+                    };
+
+                    if let Some(target) = target {
+                        vec![FrameEventFromPlayer::ReclaimOrder {
+                            id,
+                            selected: self.game_state.selected.clone(),
+                            target,
+                            queue,
                         }]
                     } else {
                         vec![]
@@ -884,6 +1180,65 @@ impl App {
             }
         }
 
+        //Right drag of a move order, units spread along the line
+        if !self.game_state.move_line.is_empty() {
+            if let Some(mouse_world_pos) = self.game_state.mouse_world_pos {
+                let last = self.game_state.move_line[self.game_state.move_line.len() - 1];
+                if (mouse_world_pos - last).xy().magnitude() > MOVE_LINE_STEP {
+                    self.game_state.move_line.push(mouse_world_pos);
+                }
+            }
+
+            if self
+                .input_state
+                .mouse_release
+                .contains(&winit::event::MouseButton::Right)
+            {
+                let line = std::mem::replace(&mut self.game_state.move_line, Vec::new());
+                if let Some(id) = self.game_state.my_player_id {
+                    let length: f32 = line
+                        .windows(2)
+                        .map(|segment| (segment[1] - segment[0]).xy().magnitude())
+                        .sum();
+                    //With control the drag only gives the facing of the units gathering at its start
+                    let facing_drag = self
+                        .input_state
+                        .key_pressed
+                        .contains(&winit::event::VirtualKeyCode::LControl);
+                    let facing = if facing_drag && length >= MIN_MOVE_LINE_LENGTH {
+                        let drag = (line[line.len() - 1] - line[0]).xy();
+                        if drag.magnitude() > 0.0 {
+                            Some(drag.into())
+                        } else {
+                            None
+                        }
+                    } else {
+                        None
+                    };
+                    let order = FrameEventFromPlayer::MoveOrder {
+                        id,
+                        selected: self.game_state.selected.clone(),
+                        mouse_world_pos: line[0],
+                        line: if length >= MIN_MOVE_LINE_LENGTH && !facing_drag {
+                            line
+                        } else {
+                            Vec::new()
+                        },
+                        facing,
+                        keep_formation: self.game_state.keep_formation,
+                        queue: self
+                            .input_state
+                            .key_pressed
+                            .contains(&winit::event::VirtualKeyCode::LShift),
+                    };
+                    log::info!("order MoveOrder from {}", id);
+                    let _ = self
+                        .sender_from_client_to_manager
+                        .try_send(client::FromClient::PlayerInput(order));
+                }
+            }
+        }
+
         self.input_state.update();
 
         let tx = self.sender_to_client.clone();
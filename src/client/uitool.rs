@@ -8,5 +8,7 @@ pub enum UiTool {
     Repair,
     Guard,
     Attack,
+    Reclaim,
+    Rally,
     Spawn(Id<botdef::BotDef>),
 }
@@ -1,5 +1,5 @@
 use super::client::*;
-use crate::botdef::BotDef;
+use crate::botdef::{BotDef, MovementClass, WeaponDef};
 use crate::model::*;
 use crate::utils::FileTree;
 use crate::*;
@@ -29,7 +29,15 @@ impl UnitEditor {
             id: utils::rand_id(),
             file_path: "src/asset/test.json".to_owned(),
             radius: 0.5,
+            mass: 1.0,
+            sight_radius: 30.0,
+            radar_radius: 0.0,
+            jammer_radius: 0.0,
+            cloak: false,
+            cloak_upkeep: 0.0,
+            decloak_radius: 0.0,
             max_life: 100,
+            armor_class: "default".to_owned(),
             turn_accel: 1.5,
             max_turn_rate: 1.5,
             accel: 0.1,
@@ -38,6 +46,17 @@ impl UnitEditor {
             build_power: 10.0,
             build_dist: 5.0,
             metal_cost: 100,
+            energy_cost: 500,
+            energy_production: 0.0,
+            energy_upkeep: 0.0,
+            metal_storage: 0.0,
+            energy_storage: 0.0,
+            extractor: false,
+            weapons: vec![WeaponDef::new()],
+            is_structure: false,
+            is_factory: false,
+            footprint: Vector2::new(1.0, 1.0),
+            movement_class: MovementClass::kbot(),
             part_tree: root,
         };
 
@@ -104,7 +123,15 @@ impl App {
                     id,
                     file_path,
                     radius,
+                    mass,
+                    sight_radius,
+                    radar_radius,
+                    jammer_radius,
+                    cloak,
+                    cloak_upkeep,
+                    decloak_radius,
                     max_life,
+                    armor_class,
                     turn_accel,
                     max_turn_rate,
                     accel,
@@ -113,6 +140,17 @@ impl App {
                     build_power,
                     build_dist,
                     metal_cost,
+                    energy_cost,
+                    energy_production,
+                    energy_upkeep,
+                    metal_storage,
+                    energy_storage,
+                    extractor,
+                    weapons,
+                    is_structure,
+                    is_factory,
+                    footprint,
+                    movement_class,
                     part_tree,
                 } = &unit_editor.botdef;
 
@@ -167,6 +205,11 @@ impl App {
                 let mut life = max_life.clone();
                 ui.drag_int(im_str!("health"), &mut life).build();
 
+                let mut armor_class_human = imgui::ImString::with_capacity(64);
+                armor_class_human.push_str(armor_class);
+                ui.input_text(im_str!("armor class"), &mut armor_class_human)
+                    .build();
+
                 let mut build_power_human = build_power * to_sec;
                 ui.drag_float(im_str!("build power (metal/sec)"), &mut build_power_human)
                     .speed(0.01)
@@ -181,14 +224,308 @@ impl App {
                     .max(100.0)
                     .build();
 
+                let mut energy_cost_ = energy_cost.clone();
+                ui.drag_int(im_str!("energy cost"), &mut energy_cost_)
+                    .build();
+
+                let mut energy_production_human = energy_production * to_sec;
+                ui.drag_float(
+                    im_str!("energy production (energy/sec)"),
+                    &mut energy_production_human,
+                )
+                .speed(0.01)
+                .min(0.0)
+                .max(1000.0)
+                .build();
+
+                let mut energy_upkeep_human = energy_upkeep * to_sec;
+                ui.drag_float(
+                    im_str!("energy upkeep (energy/sec)"),
+                    &mut energy_upkeep_human,
+                )
+                .speed(0.01)
+                .min(0.0)
+                .max(1000.0)
+                .build();
+
+                let mut mass_ = mass.clone();
+                ui.drag_float(im_str!("mass"), &mut mass_)
+                    .speed(0.1)
+                    .min(0.01)
+                    .max(100000.0)
+                    .build();
+
+                let mut sight_radius_ = sight_radius.clone();
+                ui.drag_float(im_str!("sight radius (m)"), &mut sight_radius_)
+                    .speed(0.1)
+                    .min(0.0)
+                    .max(10000.0)
+                    .build();
+
+                let mut radar_radius_ = radar_radius.clone();
+                ui.drag_float(im_str!("radar radius (m)"), &mut radar_radius_)
+                    .speed(0.1)
+                    .min(0.0)
+                    .max(10000.0)
+                    .build();
+
+                let mut jammer_radius_ = jammer_radius.clone();
+                ui.drag_float(im_str!("jammer radius (m)"), &mut jammer_radius_)
+                    .speed(0.1)
+                    .min(0.0)
+                    .max(10000.0)
+                    .build();
+
+                let mut cloak_ = *cloak;
+                ui.checkbox(im_str!("cloak"), &mut cloak_);
+
+                let mut cloak_upkeep_human = cloak_upkeep * to_sec;
+                ui.drag_float(
+                    im_str!("cloak upkeep (energy/sec)"),
+                    &mut cloak_upkeep_human,
+                )
+                .speed(0.1)
+                .min(0.0)
+                .max(10000.0)
+                .build();
+
+                let mut decloak_radius_ = decloak_radius.clone();
+                ui.drag_float(im_str!("decloak radius (m)"), &mut decloak_radius_)
+                    .speed(0.1)
+                    .min(0.0)
+                    .max(10000.0)
+                    .build();
+
+                let mut metal_storage_ = metal_storage.clone();
+                ui.drag_float(im_str!("metal storage"), &mut metal_storage_)
+                    .speed(1.0)
+                    .min(0.0)
+                    .max(100000.0)
+                    .build();
+
+                let mut energy_storage_ = energy_storage.clone();
+                ui.drag_float(im_str!("energy storage"), &mut energy_storage_)
+                    .speed(1.0)
+                    .min(0.0)
+                    .max(100000.0)
+                    .build();
+
+                let mut is_structure = *is_structure;
+                ui.checkbox(im_str!("structure"), &mut is_structure);
+
+                let mut is_factory = *is_factory;
+                ui.checkbox(im_str!("factory"), &mut is_factory);
+
+                let mut footprint_ = [footprint.x, footprint.y];
+                ui.drag_float2(im_str!("footprint (m)"), &mut footprint_)
+                    .speed(0.01)
+                    .min(0.01)
+                    .max(100.0)
+                    .build();
+
+                let mut movement_class = movement_class.clone();
+                ui.text(im_str!("movement class"));
+                if ui.small_button(im_str!("kbot")) {
+                    movement_class = MovementClass::kbot();
+                }
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("tank")) {
+                    movement_class = MovementClass::tank();
+                }
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("hover")) {
+                    movement_class = MovementClass::hover();
+                }
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("amphibious")) {
+                    movement_class = MovementClass::amphibious();
+                }
+
+                let mut max_slope_human = movement_class.max_slope.to_degrees();
+                ui.drag_float(im_str!("max slope (deg)"), &mut max_slope_human)
+                    .speed(0.1)
+                    .min(0.0)
+                    .max(90.0)
+                    .build();
+                movement_class.max_slope = max_slope_human.to_radians();
+
+                ui.drag_float(
+                    im_str!("min water depth (m)"),
+                    &mut movement_class.min_water_depth,
+                )
+                .speed(0.1)
+                .min(0.0)
+                .max(10000.0)
+                .build();
+
+                ui.drag_float(
+                    im_str!("max water depth (m)"),
+                    &mut movement_class.max_water_depth,
+                )
+                .speed(0.1)
+                .min(0.0)
+                .max(10000.0)
+                .build();
+
+                ui.drag_float(
+                    im_str!("speed on max slope (x)"),
+                    &mut movement_class.slope_speed_modifier,
+                )
+                .speed(0.01)
+                .min(0.0)
+                .max(2.0)
+                .build();
+
+                ui.checkbox(im_str!("hover"), &mut movement_class.hover);
+
+                let mut extractor = *extractor;
+                ui.checkbox(im_str!("metal extractor"), &mut extractor);
+
+                let mut weapons = weapons.clone();
+                let mut weapon_to_remove = None;
+                for (i, weapon) in weapons.iter_mut().enumerate() {
+                    ui.separator();
+                    ui.text(im_str!("weapon {}", i));
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("remove##weapon{}", i).as_ref()) {
+                        weapon_to_remove = Some(i);
+                    }
+
+                    ui.drag_float(
+                        im_str!("weapon range (m)##weapon{}", i).as_ref(),
+                        &mut weapon.range,
+                    )
+                    .speed(0.01)
+                    .min(0.01)
+                    .max(1000.0)
+                    .build();
+
+                    ui.drag_int(
+                        im_str!("weapon damage (hp)##weapon{}", i).as_ref(),
+                        &mut weapon.damage,
+                    )
+                    .build();
+
+                    let mut damage_class_human = imgui::ImString::with_capacity(64);
+                    damage_class_human.push_str(&weapon.damage_class);
+                    ui.input_text(
+                        im_str!("damage class##weapon{}", i).as_ref(),
+                        &mut damage_class_human,
+                    )
+                    .build();
+                    weapon.damage_class = damage_class_human.to_str().to_owned();
+
+                    let mut reload_human = weapon.reload_frame_count as f32 / to_sec;
+                    ui.drag_float(
+                        im_str!("weapon reload (sec)##weapon{}", i).as_ref(),
+                        &mut reload_human,
+                    )
+                    .speed(0.01)
+                    .min(0.0)
+                    .max(100.0)
+                    .build();
+
+                    let mut muzzle_speed_human = weapon.muzzle_speed * to_sec;
+                    ui.drag_float(
+                        im_str!("muzzle speed (m/sec)##weapon{}", i).as_ref(),
+                        &mut muzzle_speed_human,
+                    )
+                    .speed(0.01)
+                    .min(0.01)
+                    .max(1000.0)
+                    .build();
+
+                    let mut gravity_human = weapon.gravity * to_sec * to_sec;
+                    ui.drag_float(
+                        im_str!("projectile gravity (m/sec²)##weapon{}", i).as_ref(),
+                        &mut gravity_human,
+                    )
+                    .speed(0.01)
+                    .min(0.0)
+                    .max(100.0)
+                    .build();
+
+                    ui.drag_float(
+                        im_str!("projectile radius (m)##weapon{}", i).as_ref(),
+                        &mut weapon.projectile_radius,
+                    )
+                    .speed(0.01)
+                    .min(0.01)
+                    .max(10.0)
+                    .build();
+
+                    ui.drag_float(
+                        im_str!("aoe radius (m)##weapon{}", i).as_ref(),
+                        &mut weapon.aoe_radius,
+                    )
+                    .speed(0.01)
+                    .min(0.0)
+                    .max(100.0)
+                    .build();
+
+                    ui.drag_float(
+                        im_str!("edge damage (ratio)##weapon{}", i).as_ref(),
+                        &mut weapon.edge_damage,
+                    )
+                    .speed(0.01)
+                    .min(0.0)
+                    .max(1.0)
+                    .build();
+
+                    ui.text(im_str!("weapon joint {:?}", weapon.joint));
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("swap##weapon{}", i).as_ref()) {
+                        let next_index = weapon.joint.aim_weapon_index().map_or(0, |i| i + 1);
+                        weapon.joint = Joint::aim_weapon(next_index).unwrap_or(Joint::AimWeapon0);
+                    }
+
+                    ui.text(im_str!("targeting {:?}", weapon.targeting));
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("swap##targeting{}", i).as_ref()) {
+                        weapon.targeting = weapon.targeting.next();
+                    }
+
+                    weapon.damage = weapon.damage.max(0);
+                    weapon.reload_frame_count = (reload_human * to_sec).round() as i32;
+                    weapon.muzzle_speed = muzzle_speed_human * to_frame;
+                    weapon.gravity = gravity_human * to_frame * to_frame;
+                }
+                if let Some(i) = weapon_to_remove {
+                    weapons.remove(i);
+                }
+                if ui.small_button(im_str!("add weapon")) {
+                    let mut weapon = WeaponDef::new();
+                    weapon.joint = Joint::aim_weapon(weapons.len()).unwrap_or(Joint::AimWeapon0);
+                    weapons.push(weapon);
+                }
+
                 unit_editor.botdef.max_turn_rate = max_turn_rate_human * to_frame * to_rad;
                 unit_editor.botdef.turn_accel = turn_accel_human * to_frame * to_frame * to_rad;
                 unit_editor.botdef.max_speed = max_speed_human * to_frame;
                 unit_editor.botdef.accel = accel_human * to_frame * to_frame;
                 unit_editor.botdef.break_accel = break_accel_human * to_frame * to_frame;
                 unit_editor.botdef.max_life = life.max(0);
+                unit_editor.botdef.armor_class = armor_class_human.to_str().to_owned();
                 unit_editor.botdef.build_power = build_power_human * to_frame;
                 unit_editor.botdef.build_dist = build_dist_;
+                unit_editor.botdef.energy_cost = energy_cost_.max(0);
+                unit_editor.botdef.energy_production = energy_production_human * to_frame;
+                unit_editor.botdef.energy_upkeep = energy_upkeep_human * to_frame;
+                unit_editor.botdef.mass = mass_;
+                unit_editor.botdef.sight_radius = sight_radius_;
+                unit_editor.botdef.radar_radius = radar_radius_;
+                unit_editor.botdef.jammer_radius = jammer_radius_;
+                unit_editor.botdef.cloak = cloak_;
+                unit_editor.botdef.cloak_upkeep = cloak_upkeep_human * to_frame;
+                unit_editor.botdef.decloak_radius = decloak_radius_;
+                unit_editor.botdef.metal_storage = metal_storage_;
+                unit_editor.botdef.energy_storage = energy_storage_;
+                unit_editor.botdef.is_structure = is_structure;
+                unit_editor.botdef.is_factory = is_factory;
+                unit_editor.botdef.footprint = Vector2::new(footprint_[0], footprint_[1]);
+                unit_editor.botdef.movement_class = movement_class;
+                unit_editor.botdef.extractor = extractor;
+                unit_editor.botdef.weapons = weapons;
                 ui.separator();
                 Self::ui_part_tree(
                     ui,
@@ -3,6 +3,7 @@ extern crate nalgebra as na;
 use crate::heightmap_phy;
 
 use crate::botdef;
+use crate::frame_server;
 use crate::mobile;
 use crate::moddef;
 use crate::utils;
@@ -23,6 +24,18 @@ pub struct Player {
     pub team: u8,
     pub metal: f64,
     pub energy: f64,
+    ///metal/frame, during the last frame
+    pub metal_income: f64,
+    ///metal/frame, during the last frame
+    pub metal_expense: f64,
+    ///energy/frame, during the last frame
+    pub energy_income: f64,
+    ///energy/frame, during the last frame
+    pub energy_expense: f64,
+    ///metal, base storage plus the storage of the completed kbots
+    pub metal_storage: f64,
+    ///energy, base storage plus the storage of the completed kbots
+    pub energy_storage: f64,
 }
 
 impl Player {
@@ -34,6 +47,12 @@ impl Player {
             team: 0,
             metal: 500.0,
             energy: 500.0,
+            metal_income: 0.0,
+            metal_expense: 0.0,
+            energy_income: 0.0,
+            energy_expense: 0.0,
+            metal_storage: frame_server::BASE_METAL_STORAGE,
+            energy_storage: frame_server::BASE_ENERGY_STORAGE,
         }
     }
 }
@@ -44,17 +63,81 @@ pub enum FrameEventFromPlayer {
         id: Id<Player>,
         selected: FnvHashSet<Id<KBot>>,
         to_repair: Id<KBot>,
+        queue: bool,
     },
     ConOrder {
         id: Id<Player>,
         selected: FnvHashSet<Id<KBot>>,
         mouse_world_pos: Vector3<f32>,
         botdef_id: Id<botdef::BotDef>,
+        queue: bool,
     },
     MoveOrder {
         id: Id<Player>,
         selected: FnvHashSet<Id<KBot>>,
         mouse_world_pos: Vector3<f32>,
+        ///Right drag the units spread along, empty for a square formation around mouse_world_pos
+        line: Vec<Vector3<f32>>,
+        facing: Option<Angle>,
+        ///Move at the speed of the slowest unit
+        keep_formation: bool,
+        queue: bool,
+    },
+    AttackOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        target: Id<KBot>,
+        queue: bool,
+    },
+    GuardOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        ward: Id<KBot>,
+        queue: bool,
+    },
+    FireStateOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        fire_state: FireState,
+    },
+    CloakOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        cloak: bool,
+    },
+    ReclaimOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        target: Reclaimable,
+        queue: bool,
+    },
+    ///Add count units to the queue of the selected factories, negative to remove some
+    FactoryQueueOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        botdef_id: Id<botdef::BotDef>,
+        count: i32,
+    },
+    FactoryRepeatOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        repeat: bool,
+    },
+    RallyPointOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        mouse_world_pos: Vector3<f32>,
+    },
+    ///Self destruct the selected unfinished kbots, refunding what they cost so far
+    CancelOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+    },
+    ShareResources {
+        id: Id<Player>,
+        to: Id<Player>,
+        metal: f64,
+        energy: f64,
     },
     ReplaceFrame(Frame),
 }
@@ -111,6 +194,11 @@ pub struct Frame {
     pub heightmap_phy: Option<heightmap_phy::HeightmapPhy>,
     pub players: FnvHashMap<Id<Player>, Player>,
     pub kbots: FnvHashMap<Id<KBot>, KBot>,
+    pub features: FnvHashMap<Id<Feature>, Feature>,
+    ///Per team, see frame_server::visibility::filter_frame
+    pub visibility: FnvHashMap<u8, frame_server::visibility::TeamVisibility>,
+    ///Only filled in the view of a team
+    pub radar_contacts: Vec<frame_server::visibility::RadarContact>,
     pub moddef: moddef::ModDef,
     // relevant to send to client once
     pub bot_defs: FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
@@ -130,6 +218,9 @@ impl Frame {
             players: FnvHashMap::default(),
             moddef: moddef::ModDef::new(),
             kbots: FnvHashMap::default(),
+            features: FnvHashMap::default(),
+            visibility: FnvHashMap::default(),
+            radar_contacts: Vec::new(),
             kinematic_projectiles: FnvHashMap::default(),
             arrows: Vec::new(),
             explosions: Vec::new(),
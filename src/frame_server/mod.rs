@@ -3,12 +3,41 @@ use crate::frame::*;
 use crate::botdef;
 use crate::heightmap_phy;
 use crate::mobile::*;
+use crate::moddef;
 use crate::utils::*;
 use crossbeam_channel::{Receiver, Sender};
 use fnv::{FnvHashMap, FnvHashSet};
 use na::{Matrix4, Point3, Vector2, Vector3};
 use std::time::Instant;
 
+pub mod pathfinding;
+pub mod visibility;
+
+///frame, how long a kbot remembers who shot it
+const ATTACKER_MEMORY: i32 = 50;
+///m, distance a guard tries to keep with its ward
+const GUARD_DISTANCE: f32 = 4.0;
+///m, distance at which a waypoint is considered reached
+const WAYPOINT_REACHED_DISTANCE: f32 = pathfinding::CELL_SIZE / 2.0;
+///m, how far a moving destination can go before the path is computed again
+const REPATH_DISTANCE: f32 = pathfinding::CELL_SIZE;
+///Units of a movement class going to the same region from which they share a flow field instead of computing paths
+const FLOW_FIELD_MIN_UNITS: usize = 16;
+///rad, the facing of a move order is reached under this difference
+const FACING_TOLERANCE: f32 = 0.05;
+///frame, a cloaked kbot stays revealed this long after firing
+const DECLOAK_FRAME_COUNT: i32 = 30;
+///m, free space between two units of a formation
+const FORMATION_GAP: f32 = 1.0;
+///m, a move line whose ends are closer than this takes its axis from its first segment
+const LINE_AXIS_EPSILON: f32 = 0.01;
+///Part of the metal_cost left in the wreck of a kbot
+const WRECK_METAL_RATIO: f32 = 0.5;
+///metal, storage of a player without any storage building
+pub const BASE_METAL_STORAGE: f64 = 1000.0;
+///energy, storage of a player without any storage building
+pub const BASE_ENERGY_STORAGE: f64 = 1000.0;
+
 pub enum ToFrameServer {
     DataToComputeNextFrame(DataToComputeNextFrame),
 }
@@ -21,6 +50,7 @@ pub struct FrameServerCache {
     pub grid: Vec<Vec<Id<KBot>>>,
     pub small_grid: Vec<Vec<Id<KBot>>>,
     pub heightmap_phy: Option<heightmap_phy::HeightmapPhy>,
+    pub pathfinding: pathfinding::Pathfinding,
 }
 
 impl FrameServerCache {
@@ -61,6 +91,7 @@ impl FrameServerCache {
             grid: Vec::new(),
             small_grid: Vec::new(),
             heightmap_phy: None,
+            pathfinding: pathfinding::Pathfinding::new(),
         }
     }
 
@@ -76,6 +107,7 @@ impl FrameServerCache {
             match event {
                 FrameEventFromPlayer::ReplaceFrame(frame) => {
                     self.heightmap_phy = frame.heightmap_phy.clone();
+                    self.pathfinding.clear();
                     replacer = Some(frame.clone());
                     log::trace!("Replacing frame");
                 }
@@ -98,19 +130,48 @@ impl FrameServerCache {
                     id,
                     selected,
                     mouse_world_pos,
+                    line,
+                    facing,
+                    keep_formation,
+                    queue,
                 } => {
                     //TODO Validate selected are owned by id
-                    update_mobile_target(mouse_world_pos, &selected, &mut frame.kbots);
+                    update_mobile_target(
+                        mouse_world_pos,
+                        &line,
+                        facing,
+                        keep_formation,
+                        &selected,
+                        &mut frame.kbots,
+                        &frame.bot_defs,
+                        queue,
+                    );
                 }
                 FrameEventFromPlayer::ConOrder {
                     id,
                     selected,
                     mouse_world_pos,
                     botdef_id,
+                    queue,
                 } => {
                     //TODO Validate selected are owned by id && botdef_id is constructable by at least 1 selected
 
                     let botdef = frame.bot_defs.get(&botdef_id).unwrap();
+                    let valid_placement = match &self.heightmap_phy {
+                        Some(heightmap_phy) => is_placement_valid(
+                            botdef,
+                            mouse_world_pos,
+                            heightmap_phy,
+                            &frame.kbots,
+                            &frame.bot_defs,
+                        ),
+                        None => false,
+                    };
+                    if !valid_placement {
+                        log::debug!("Rejected placement of {} at {}", botdef_id, mouse_world_pos);
+                        continue;
+                    }
+
                     let mut m = KBot::new(Point3::from(mouse_world_pos), botdef, id);
                     m.team = frame.players.get(&id).unwrap().team;
                     m.con_completed = std::f32::MIN_POSITIVE;
@@ -118,7 +179,7 @@ impl FrameServerCache {
 
                     for selected_raw_id in &selected {
                         for kbot in frame.kbots.get_mut(selected_raw_id) {
-                            kbot.current_command = Command::Build(m.id.clone())
+                            kbot.push_command(Command::Build(m.id.clone()), queue)
                         }
                     }
 
@@ -131,10 +192,210 @@ impl FrameServerCache {
                     id,
                     selected,
                     to_repair,
+                    queue,
+                } => {
+                    for selected_raw_id in &selected {
+                        for kbot in frame.kbots.get_mut(selected_raw_id) {
+                            kbot.push_command(Command::Repair(to_repair), queue)
+                        }
+                    }
+                }
+
+                FrameEventFromPlayer::AttackOrder {
+                    id,
+                    selected,
+                    target,
+                    queue,
+                } => {
+                    for selected_raw_id in &selected {
+                        if *selected_raw_id != target {
+                            for kbot in frame.kbots.get_mut(selected_raw_id) {
+                                if kbot.player_id == id {
+                                    kbot.push_command(Command::Attack(target), queue)
+                                }
+                            }
+                        }
+                    }
+                }
+
+                FrameEventFromPlayer::GuardOrder {
+                    id,
+                    selected,
+                    ward,
+                    queue,
+                } => {
+                    for selected_raw_id in &selected {
+                        if *selected_raw_id != ward {
+                            for kbot in frame.kbots.get_mut(selected_raw_id) {
+                                if kbot.player_id == id {
+                                    kbot.push_command(Command::Guard(ward), queue)
+                                }
+                            }
+                        }
+                    }
+                }
+
+                FrameEventFromPlayer::ReclaimOrder {
+                    id,
+                    selected,
+                    target,
+                    queue,
+                } => {
+                    for selected_raw_id in &selected {
+                        if Reclaimable::KBot(*selected_raw_id) != target {
+                            for kbot in frame.kbots.get_mut(selected_raw_id) {
+                                if kbot.player_id == id {
+                                    kbot.push_command(Command::Reclaim(target), queue)
+                                }
+                            }
+                        }
+                    }
+                }
+
+                FrameEventFromPlayer::FactoryQueueOrder {
+                    id,
+                    selected,
+                    botdef_id,
+                    count,
                 } => {
                     for selected_raw_id in &selected {
                         for kbot in frame.kbots.get_mut(selected_raw_id) {
-                            kbot.current_command = Command::Repair(to_repair)
+                            let is_factory =
+                                frame.bot_defs.get(&kbot.botdef_id).unwrap().is_factory;
+                            let can_build = frame
+                                .moddef
+                                .con_map
+                                .get(&kbot.botdef_id)
+                                .map_or(false, |units| units.contains(&botdef_id));
+                            if kbot.player_id == id && is_factory && can_build {
+                                kbot.factory_queue.add(botdef_id, count);
+                            }
+                        }
+                    }
+                }
+
+                FrameEventFromPlayer::FactoryRepeatOrder {
+                    id,
+                    selected,
+                    repeat,
+                } => {
+                    for selected_raw_id in &selected {
+                        for kbot in frame.kbots.get_mut(selected_raw_id) {
+                            if kbot.player_id == id {
+                                kbot.factory_queue.repeat = repeat;
+                            }
+                        }
+                    }
+                }
+
+                FrameEventFromPlayer::RallyPointOrder {
+                    id,
+                    selected,
+                    mouse_world_pos,
+                } => {
+                    for selected_raw_id in &selected {
+                        for kbot in frame.kbots.get_mut(selected_raw_id) {
+                            if kbot.player_id == id {
+                                kbot.rally_point = Some(Point3::from(mouse_world_pos));
+                            }
+                        }
+                    }
+                }
+
+                FrameEventFromPlayer::CancelOrder { id, selected } => {
+                    for selected_raw_id in &selected {
+                        let cancelled = match frame.kbots.get_mut(selected_raw_id) {
+                            Some(kbot) if kbot.player_id == id && kbot.con_completed < 1.0 => {
+                                let botdef = frame.bot_defs.get(&kbot.botdef_id).unwrap();
+                                let player = frame.players.get_mut(&id).unwrap();
+                                player.metal +=
+                                    kbot.con_completed as f64 * botdef.metal_cost as f64;
+                                player.energy +=
+                                    kbot.con_completed as f64 * botdef.energy_cost as f64;
+                                //Removed with the other dead kbots in update_units, no wreck left
+                                kbot.con_completed = 0.0;
+                                kbot.life = 0;
+                                true
+                            }
+                            _ => false,
+                        };
+
+                        if cancelled {
+                            for kbot in frame.kbots.values_mut() {
+                                if kbot.factory_building == Some(*selected_raw_id) {
+                                    kbot.factory_building = None;
+                                }
+                                let was_front = match kbot.commands.front() {
+                                    Some(Command::Build(to_build))
+                                    | Some(Command::Repair(to_build)) => {
+                                        to_build == selected_raw_id
+                                    }
+                                    _ => false,
+                                };
+                                kbot.commands.retain(|command| match command {
+                                    Command::Build(to_build) | Command::Repair(to_build) => {
+                                        to_build != selected_raw_id
+                                    }
+                                    _ => true,
+                                });
+                                if was_front {
+                                    kbot.move_target = None;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                FrameEventFromPlayer::ShareResources {
+                    id,
+                    to,
+                    metal,
+                    energy,
+                } => {
+                    let same_team = match (frame.players.get(&id), frame.players.get(&to)) {
+                        (Some(from), Some(to)) => from.team == to.team && from.id != to.id,
+                        _ => false,
+                    };
+                    if same_team {
+                        let from = frame.players.get_mut(&id).unwrap();
+                        let metal = metal.max(0.0).min(from.metal);
+                        let energy = energy.max(0.0).min(from.energy);
+                        from.metal -= metal;
+                        from.energy -= energy;
+
+                        //Overflow past the storage of the receiver is handled in update_units
+                        let to = frame.players.get_mut(&to).unwrap();
+                        to.metal += metal;
+                        to.energy += energy;
+                    }
+                }
+
+                FrameEventFromPlayer::CloakOrder {
+                    id,
+                    selected,
+                    cloak,
+                } => {
+                    for selected_raw_id in &selected {
+                        for kbot in frame.kbots.get_mut(selected_raw_id) {
+                            if kbot.player_id == id
+                                && frame.bot_defs.get(&kbot.botdef_id).unwrap().cloak
+                            {
+                                kbot.cloak_wanted = cloak;
+                            }
+                        }
+                    }
+                }
+
+                FrameEventFromPlayer::FireStateOrder {
+                    id,
+                    selected,
+                    fire_state,
+                } => {
+                    for selected_raw_id in &selected {
+                        for kbot in frame.kbots.get_mut(selected_raw_id) {
+                            if kbot.player_id == id {
+                                kbot.fire_state = fire_state;
+                            }
                         }
                     }
                 }
@@ -152,6 +413,7 @@ impl FrameServerCache {
             update_units(
                 &mut frame_profiler,
                 &mut frame.kbots,
+                &mut frame.features,
                 &mut frame.kbots_dead,
                 &mut frame.kinematic_projectiles_dead,
                 &mut frame.kinematic_projectiles_birth,
@@ -162,9 +424,16 @@ impl FrameServerCache {
                 &mut frame.players,
                 &mut self.grid,
                 &mut self.small_grid,
+                &mut self.pathfinding,
                 &mut frame.explosions,
                 &frame.bot_defs,
+                &frame.moddef,
             );
+
+            let start = Instant::now();
+            frame.visibility =
+                visibility::compute(&frame.kbots, &frame.players, &frame.bot_defs, heightmap);
+            frame_profiler.add("2 visibility", start.elapsed());
         }
         frame_profiler.add("0 update_units", start_update_units.elapsed());
         frame_profiler.add("total", start.elapsed());
@@ -177,75 +446,266 @@ impl FrameServerCache {
     }
 }
 
+///Inside the map, above water, not too steep and not overlapping the footprint of a structure
+///or an unfinished unit, finished units can walk away
+pub fn is_placement_valid(
+    botdef: &botdef::BotDef,
+    position: Vector3<f32>,
+    heightmap_phy: &heightmap_phy::HeightmapPhy,
+    kbots: &FnvHashMap<Id<KBot>, KBot>,
+    bot_defs: &FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
+) -> bool {
+    let half = botdef.footprint / 2.0;
+    let min = position.xy() - half;
+    let max = position.xy() + half;
+
+    let in_bounds = min.x >= 0.0
+        && min.y >= 0.0
+        && max.x <= heightmap_phy.width as f32 - 1.0
+        && max.y <= heightmap_phy.height as f32 - 1.0;
+    if !in_bounds {
+        return false;
+    }
+
+    //Sample the corners and the center of the footprint
+    let samples = [
+        Vector2::new(min.x, min.y),
+        Vector2::new(max.x, min.y),
+        Vector2::new(min.x, max.y),
+        Vector2::new(max.x, max.y),
+        position.xy(),
+    ];
+    for sample in samples.iter() {
+        let water_depth = heightmap_phy.water_depth(sample.x, sample.y);
+        let slope = heightmap_phy.normal(sample.x, sample.y).z.acos();
+        if !botdef.movement_class.can_cross(slope, water_depth) {
+            return false;
+        }
+    }
+
+    kbots.values().all(|kbot| {
+        let other = bot_defs.get(&kbot.botdef_id).unwrap();
+        if other.is_structure {
+            let other_half = other.footprint / 2.0;
+            let other_min = kbot.position.coords.xy() - other_half;
+            let other_max = kbot.position.coords.xy() + other_half;
+            max.x <= other_min.x
+                || other_max.x <= min.x
+                || max.y <= other_min.y
+                || other_max.y <= min.y
+        } else if kbot.con_completed < 1.0 {
+            //Closest point of the footprint to the unit
+            let closest = Vector2::new(
+                kbot.position.x.max(min.x).min(max.x),
+                kbot.position.y.max(min.y).min(max.y),
+            );
+            (kbot.position.coords.xy() - closest).magnitude() >= other.radius
+        } else {
+            true
+        }
+    })
+}
+
 pub fn update_mobile_target(
     mouse_world_pos: Vector3<f32>,
+    line: &[Vector3<f32>],
+    facing: Option<Angle>,
+    keep_formation: bool,
     selected: &FnvHashSet<Id<KBot>>,
     kbots: &mut FnvHashMap<Id<KBot>, KBot>,
+    bot_defs: &FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
+    queue: bool,
 ) {
-    let selected_count = selected.len();
-    let formation_w = (selected_count as f32).sqrt().ceil() as i32;
-
-    let mut spot = Vec::<Vector3<f32>>::new();
-    for i in 0..formation_w {
-        for j in 0..formation_w {
-            spot.push(
-                mouse_world_pos
-                    + Vector3::new(
-                        i as f32 + 0.5 - formation_w as f32 / 2.0,
-                        j as f32 + 0.5 - formation_w as f32 / 2.0,
-                        0.0,
-                    ) * 4.0,
-            )
-        }
-    }
-
     let mut center = Vector3::new(0.0, 0.0, 0.0);
     let mut tap = 0.0;
 
     let mut id_to_pos = Vec::new();
+    let mut max_radius: f32 = 0.0;
+    let mut slowest: Option<f32> = None;
     for &s in selected.iter() {
         if let Some(mobile) = kbots.get(&s) {
-            id_to_pos.push((mobile.id, mobile.position.coords));
+            let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+            id_to_pos.push((mobile.id, mobile.position.coords, botdef.radius));
             center += mobile.position.coords;
             tap += 1.0;
+            max_radius = max_radius.max(botdef.radius);
+            slowest = Some(slowest.unwrap_or(botdef.max_speed).min(botdef.max_speed));
         }
     }
+    if id_to_pos.is_empty() {
+        return;
+    }
     center /= tap;
+    let speed = if keep_formation { slowest } else { None };
+
+    let line_length: f32 = line
+        .windows(2)
+        .map(|segment| (segment[1] - segment[0]).xy().magnitude())
+        .sum();
+
+    let (spots, facing): (Vec<(Id<KBot>, Vector3<f32>)>, _) = if line_length > 0.0 {
+        //A line ending where it started has no axis from its ends, use its first segment
+        let ends = (line[line.len() - 1] - line[0]).xy();
+        let line_axis = if ends.magnitude() > LINE_AXIS_EPSILON {
+            ends.normalize()
+        } else {
+            line.windows(2)
+                .map(|segment| (segment[1] - segment[0]).xy())
+                .find(|segment| segment.magnitude() > 0.0)
+                .unwrap()
+                .normalize()
+        };
+        //Face away from the units by default
+        let facing_dir: Vector2<f32> = match facing {
+            Some(facing) => facing.into(),
+            None => {
+                let normal = Vector2::new(-line_axis.y, line_axis.x);
+                let line_center = (line[0] + line[line.len() - 1]) / 2.0;
+                if normal.dot(&(line_center - center).xy()) < 0.0 {
+                    -normal
+                } else {
+                    normal
+                }
+            }
+        };
+        let back = Vector3::new(-facing_dir.x, -facing_dir.y, 0.0);
 
-    let axis = (mouse_world_pos - center).normalize();
+        fn point_along(line: &[Vector3<f32>], mut distance: f32) -> Vector3<f32> {
+            for segment in line.windows(2) {
+                let length = (segment[1] - segment[0]).xy().magnitude();
+                if distance <= length && length > 0.0 {
+                    return segment[0] + (segment[1] - segment[0]) * (distance / length);
+                }
+                distance -= length;
+            }
+            line[line.len() - 1]
+        }
 
-    let mut projected_spot: Vec<_> = spot
-        .iter()
-        .enumerate()
-        .map(|(index, v)| (index, v.dot(&axis)))
-        .collect();
+        //Front units fill the line, the others make rows behind it
+        id_to_pos.sort_by(|(_, a, _), (_, b, _)| {
+            b.xy()
+                .dot(&facing_dir)
+                .partial_cmp(&a.xy().dot(&facing_dir))
+                .unwrap()
+        });
 
-    projected_spot.sort_by(|(_, proj), (_, proj2)| {
-        if proj > proj2 {
-            std::cmp::Ordering::Greater
-        } else {
-            std::cmp::Ordering::Less
-        }
-    });
+        let mut spots = Vec::new();
+        let mut row_offset = 0.0;
+        let mut row_start = 0;
+        while row_start < id_to_pos.len() {
+            let mut row_end = row_start;
+            let mut row_width = 0.0;
+            while row_end < id_to_pos.len() {
+                let width = 2.0 * id_to_pos[row_end].2
+                    + if row_end > row_start {
+                        FORMATION_GAP
+                    } else {
+                        0.0
+                    };
+                if row_end > row_start && row_width + width > line_length {
+                    break;
+                }
+                row_width += width;
+                row_end += 1;
+            }
 
-    let mut id_to_proj: Vec<_> = id_to_pos
-        .iter()
-        .map(|(index, v)| (index, v.dot(&axis)))
-        .collect();
+            let row = &mut id_to_pos[row_start..row_end];
+            row.sort_by(|(_, a, _), (_, b, _)| {
+                a.xy()
+                    .dot(&line_axis)
+                    .partial_cmp(&b.xy().dot(&line_axis))
+                    .unwrap()
+            });
 
-    id_to_proj.sort_by(|(_, proj), (_, proj2)| {
-        if proj > proj2 {
-            std::cmp::Ordering::Greater
-        } else {
-            std::cmp::Ordering::Less
+            //Spread the units along the whole line
+            let (mut distance, extra_gap) = if row.len() > 1 {
+                (
+                    0.0,
+                    (line_length - row_width).max(0.0) / (row.len() - 1) as f32,
+                )
+            } else {
+                ((line_length - row_width) / 2.0, 0.0)
+            };
+            let mut row_depth: f32 = 0.0;
+            for (id, _, radius) in row.iter() {
+                spots.push((
+                    *id,
+                    point_along(line, distance + radius) + back * (row_offset + radius),
+                ));
+                distance += 2.0 * radius + FORMATION_GAP + extra_gap;
+                row_depth = row_depth.max(2.0 * radius);
+            }
+
+            row_offset += row_depth + FORMATION_GAP;
+            row_start = row_end;
+        }
+        (spots, Some(facing_dir.into()))
+    } else {
+        let formation_w = (id_to_pos.len() as f32).sqrt().ceil() as i32;
+        let spacing = 2.0 * max_radius + FORMATION_GAP;
+
+        let mut spot = Vec::<Vector3<f32>>::new();
+        for i in 0..formation_w {
+            for j in 0..formation_w {
+                spot.push(
+                    mouse_world_pos
+                        + Vector3::new(
+                            i as f32 + 0.5 - formation_w as f32 / 2.0,
+                            j as f32 + 0.5 - formation_w as f32 / 2.0,
+                            0.0,
+                        ) * spacing,
+                )
+            }
         }
-    });
 
-    for ((id, _), (spot_id, _)) in id_to_proj.iter().zip(&projected_spot[..]) {
-        if let Some(mobile) = kbots.get_mut(id) {
+        let axis = (mouse_world_pos - center).normalize();
+
+        let mut projected_spot: Vec<_> = spot
+            .iter()
+            .enumerate()
+            .map(|(index, v)| (index, v.dot(&axis)))
+            .collect();
+
+        projected_spot.sort_by(|(_, proj), (_, proj2)| {
+            if proj > proj2 {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Less
+            }
+        });
+
+        let mut id_to_proj: Vec<_> = id_to_pos
+            .iter()
+            .map(|(index, v, _)| (index, v.dot(&axis)))
+            .collect();
+
+        id_to_proj.sort_by(|(_, proj), (_, proj2)| {
+            if proj > proj2 {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Less
+            }
+        });
+
+        let spots = id_to_proj
+            .iter()
+            .zip(&projected_spot[..])
+            .map(|((id, _), (spot_id, _))| (**id, spot[*spot_id]))
+            .collect();
+        (spots, facing)
+    };
+
+    for (id, spot) in spots {
+        if let Some(mobile) = kbots.get_mut(&id) {
             log::trace!("New order for {}", mobile.id);
-            mobile.move_target = Some(Point3::<f32>::from(spot[*spot_id]));
-            mobile.current_command = Command::None;
+            mobile.push_command(
+                Command::Move {
+                    target: Point3::<f32>::from(spot),
+                    facing,
+                    speed,
+                },
+                queue,
+            );
         }
     }
 }
@@ -253,6 +713,7 @@ pub fn update_mobile_target(
 pub fn update_units(
     frame_profiler: &mut ProfilerMap,
     kbots: &mut FnvHashMap<Id<KBot>, KBot>,
+    features: &mut FnvHashMap<Id<Feature>, Feature>,
     kbots_dead: &mut FnvHashSet<Id<KBot>>,
     kinematic_projectiles_dead: &mut Vec<Id<KinematicProjectile>>,
     kinematic_projectiles_birth: &mut Vec<KinematicProjectile>,
@@ -263,8 +724,10 @@ pub fn update_units(
     players: &mut FnvHashMap<Id<Player>, Player>,
     grid: &mut Vec<Vec<Id<KBot>>>,
     small_grid: &mut Vec<Vec<Id<KBot>>>,
+    pathfinding: &mut pathfinding::Pathfinding,
     explosions: &mut Vec<ExplosionEvent>,
     bot_defs: &FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
+    moddef: &moddef::ModDef,
 ) {
     let start = std::time::Instant::now();
     let cell_size = 4;
@@ -308,6 +771,33 @@ pub fn update_units(
 
     frame_profiler.add("01  grid", start.elapsed());
 
+    fn index_aabb(
+        position: Vector3<f32>,
+        radius: f32,
+        cell_size: usize,
+        grid_w: usize,
+        grid_h: usize,
+    ) -> Vec<usize> {
+        let mut indices = Vec::new();
+        let min_x = (position.x - radius * 1.0).floor() as usize;
+        let max_x = (position.x + radius * 1.0).ceil() as usize;
+        let min_y = (position.y - radius * 1.0).floor() as usize;
+        let max_y = (position.y + radius * 1.0).ceil() as usize;
+
+        let min_x = (min_x / cell_size).min(grid_w - 1);
+        let max_x = ((max_x + 1) / cell_size).min(grid_w - 1);
+        let min_y = (min_y / cell_size).min(grid_h - 1);
+        let max_y = ((max_y + 1) / cell_size).min(grid_h - 1);
+
+        for i in min_x..=max_x {
+            for j in min_y..=max_y {
+                // println!("INSERTION {} {} {}", i, j, id);
+                indices.push(i + j * grid_w);
+            }
+        }
+        indices
+    }
+
     //AABB for kbot and proj
     {
         let start = std::time::Instant::now();
@@ -323,35 +813,65 @@ pub fn update_units(
             }
         }
 
-        fn index_aabb(
+        ///Damage every kbot in reach of an impact, from full damage at the center to edge_damage at aoe_radius
+        fn explode(
             position: Vector3<f32>,
-            radius: f32,
+            weapon: &botdef::WeaponDef,
+            projectile_radius: f32,
+            from: Id<KBot>,
+            frame_count: i32,
+            kbots: &mut FnvHashMap<Id<KBot>, KBot>,
+            bot_defs: &FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
+            moddef: &moddef::ModDef,
+            small_grid: &Vec<Vec<Id<KBot>>>,
             cell_size: usize,
             grid_w: usize,
-        ) -> Vec<usize> {
-            let mut indices = Vec::new();
-            let min_x = (position.x - radius * 1.0).floor() as usize;
-            let max_x = (position.x + radius * 1.0).ceil() as usize;
-            let min_y = (position.y - radius * 1.0).floor() as usize;
-            let max_y = (position.y + radius * 1.0).ceil() as usize;
-
-            let min_x = min_x / cell_size;
-            let max_x = (max_x + 1) / cell_size;
-            let min_y = min_y / cell_size;
-            let max_y = (max_y + 1) / cell_size;
-
-            for i in (min_x..=max_x).step_by(cell_size) {
-                for j in (min_y..=max_y).step_by(cell_size) {
-                    // println!("INSERTION {} {} {}", i, j, id);
-                    indices.push(i + j * grid_w);
+            grid_h: usize,
+            explosions: &mut Vec<ExplosionEvent>,
+        ) {
+            let reach = weapon.aoe_radius + projectile_radius;
+            let kbots_in_reach: FnvHashSet<_> =
+                index_aabb(position, reach, cell_size, grid_w, grid_h)
+                    .iter()
+                    .map(|index| small_grid[*index].clone())
+                    .flatten()
+                    .collect();
+
+            for kbot_id in kbots_in_reach.iter() {
+                let kbot = kbots.get_mut(kbot_id).unwrap();
+                let kbot_botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+                let kbot_radius = kbot_botdef.radius;
+                //Distance from the surface of the projectile to the surface of the kbot
+                let distance = ((kbot.position.coords - position).magnitude()
+                    - kbot_radius
+                    - projectile_radius)
+                    .max(0.0);
+                if distance <= weapon.aoe_radius {
+                    let falloff = if weapon.aoe_radius > 0.0 {
+                        1.0 - (1.0 - weapon.edge_damage) * distance / weapon.aoe_radius
+                    } else {
+                        1.0
+                    };
+                    let multiplier =
+                        moddef.damage_multiplier(&weapon.damage_class, &kbot_botdef.armor_class);
+                    let damage = (weapon.damage as f32 * multiplier * falloff).round() as i32;
+                    kbot.life = (kbot.life - damage).max(0);
+                    kbot.last_attacker = Some(from);
+                    kbot.frame_last_hit = frame_count;
                 }
             }
-            indices
+
+            explosions.push(ExplosionEvent {
+                position: Point3::from(position),
+                size: weapon.aoe_radius,
+                life_time: 0.8,
+            });
         }
 
         for (id, kbot) in kbots.iter() {
             let radius = bot_defs.get(&kbot.botdef_id).unwrap().radius;
-            for index in index_aabb(kbot.position.coords, radius, cell_size, grid_w).iter() {
+            for index in index_aabb(kbot.position.coords, radius, cell_size, grid_w, grid_h).iter()
+            {
                 small_grid[*index].push(*id);
             }
         }
@@ -364,7 +884,9 @@ pub fn update_units(
             for proj in kinematic_projectiles.values_mut() {
                 let current_pos = proj.position_at(frame_count - 1);
                 let next_pos = proj.position_at(frame_count);
+                let weapon = &bot_defs.get(&proj.botdef_id).unwrap().weapons[proj.weapon_index];
 
+                let mut impact = None;
                 {
                     //Slowly interpolate to not miss collisions
                     let step_size = proj.radius * 1.0;
@@ -381,7 +903,8 @@ pub fn update_units(
                         }
 
                         //Checking collision with current_interp
-                        let indices = index_aabb(current_interp, proj.radius, cell_size, grid_w);
+                        let indices =
+                            index_aabb(current_interp, proj.radius, cell_size, grid_w, grid_h);
 
                         let kbots_in_proximity: FnvHashSet<_> = indices
                             .iter()
@@ -391,7 +914,7 @@ pub fn update_units(
                         // &small_grid_kbot[index];
 
                         'bot_test: for kbot_id in kbots_in_proximity.iter() {
-                            let kbot = kbots.get_mut(kbot_id).unwrap();
+                            let kbot = kbots.get(kbot_id).unwrap();
                             let distance_to_target =
                                 (kbot.position.coords - current_interp).magnitude();
 
@@ -399,19 +922,40 @@ pub fn update_units(
                             let kbot_radius = bot_defs.get(&kbot.botdef_id).unwrap().radius;
                             if distance_to_target < (kbot_radius + proj.radius) {
                                 //Colission between Kbot and projectile
-                                kbot.life = (kbot.life - 10).max(0);
-                                proj.death_frame = frame_count;
-                                explosions.push(ExplosionEvent {
-                                    position: Point3::from(current_interp),
-                                    size: 0.5,
-                                    life_time: 0.8,
-                                });
+                                impact = Some(current_interp);
                                 break 'interp;
                             }
                         }
+
+                        //Checking collision with the ground, hills give cover
+                        let ground_z = heightmap_phy.z_linear(current_interp.x, current_interp.y);
+                        if current_interp.z < ground_z {
+                            impact =
+                                Some(Vector3::new(current_interp.x, current_interp.y, ground_z));
+                            break 'interp;
+                        }
                     }
                 }
 
+                if let Some(impact) = impact {
+                    proj.death_frame = frame_count;
+                    explode(
+                        impact,
+                        weapon,
+                        proj.radius,
+                        proj.from,
+                        frame_count,
+                        kbots,
+                        bot_defs,
+                        moddef,
+                        small_grid,
+                        cell_size,
+                        grid_w,
+                        grid_h,
+                        explosions,
+                    );
+                }
+
                 if proj.death_frame == frame_count {
                     kinematic_projectiles_dead.push(proj.id);
                 }
@@ -448,40 +992,140 @@ pub fn update_units(
         let start = std::time::Instant::now();
         struct Shot {
             bot: Id<KBot>,
-            target: Vector3<f32>,
+            weapon_index: usize,
+            target_id: Id<KBot>,
+            position_at_birth: Point3<f32>,
+            speed: Vector3<f32>,
         };
 
         let mut shots = Vec::new();
 
         for (me, me_kbot) in kbots.iter() {
             if me_kbot.con_completed == 1.0 {
-                let grid_pos = grid_pos(me_kbot);
-
+                let botdef = bot_defs.get(&me_kbot.botdef_id).unwrap();
+                if botdef.weapons.is_empty() {
+                    continue;
+                }
                 let my_team = id_to_team.get(me).unwrap();
 
-                // let ennemies_in_cell = &team_to_ennemy_grid.get(my_team).unwrap()[grid_pos];
-
-                let mut ennemies_in_cell: Vec<Id<KBot>> = grid[grid_pos].clone();
-                let to_remove = ennemies_in_cell.iter().position(|e| e == me).unwrap();
-                ennemies_in_cell.remove(to_remove);
-
-                let can_shoot =// *my_team == 0&&
-                 frame_count - me_kbot.frame_last_shot > me_kbot.reload_frame_count;
-                if can_shoot {
-                    //We choose the first ennemy in the cell, we could sort by distance or something else here
-                    //TODO Configurable strategy
-                    'meloop: for potential_ennemy in ennemies_in_cell {
-                        if id_to_team.get(&potential_ennemy).unwrap() != my_team {
-                            let ennemy_kbot = kbots.get(&potential_ennemy).unwrap();
-                            if (ennemy_kbot.position.coords - me_kbot.position.coords).magnitude()
-                                < 6.0
-                            {
-                                shots.push(Shot {
-                                    bot: *me,
-                                    target: ennemy_kbot.position.coords,
-                                });
-                                break 'meloop;
+                let in_range = |target: &KBot, weapon: &botdef::WeaponDef| {
+                    (target.position.coords - me_kbot.position.coords).magnitude() < weapon.range
+                };
+
+                //Each weapon slot reloads and chooses its target on its own
+                for (weapon_index, (weapon, weapon_state)) in botdef
+                    .weapons
+                    .iter()
+                    .zip(me_kbot.weapons.iter())
+                    .enumerate()
+                {
+                    let can_shoot = // *my_team == 0&&
+                        frame_count - weapon_state.frame_last_shot > weapon.reload_frame_count;
+                    if !can_shoot {
+                        continue;
+                    }
+
+                    let is_ennemy_in_range = |id: &Id<KBot>| match kbots.get(id) {
+                        Some(kbot) => {
+                            id_to_team.get(id) != Some(my_team)
+                                && !kbot.cloaked
+                                && in_range(kbot, weapon)
+                        }
+                        None => false,
+                    };
+
+                    //Who shot at our ward, then who shot at us
+                    let retaliation_target = match me_kbot.commands.front() {
+                        Some(Command::Guard(ward)) => kbots
+                            .get(ward)
+                            .and_then(|ward| ward.recent_attacker(frame_count, ATTACKER_MEMORY))
+                            .filter(is_ennemy_in_range),
+                        _ => None,
+                    }
+                    .or_else(|| {
+                        me_kbot
+                            .recent_attacker(frame_count, ATTACKER_MEMORY)
+                            .filter(is_ennemy_in_range)
+                    });
+
+                    let target = match (me_kbot.commands.front(), me_kbot.fire_state) {
+                        //An explicit target is the only one we are allowed to shoot at
+                        (Some(Command::Attack(target)), _) => kbots
+                            .get(target)
+                            .filter(|target_kbot| in_range(*target_kbot, weapon))
+                            .map(|_| *target),
+                        (_, FireState::HoldFire) => None,
+                        (_, FireState::ReturnFire) => retaliation_target,
+                        (_, FireState::FireAtWill) => retaliation_target.or_else(|| {
+                            //Every cell covered by the range of the weapon
+                            let candidates: FnvHashSet<Id<KBot>> = index_aabb(
+                                me_kbot.position.coords,
+                                weapon.range,
+                                cell_size,
+                                grid_w,
+                                grid_h,
+                            )
+                            .iter()
+                            .flat_map(|index| small_grid[*index].iter().copied())
+                            .collect();
+                            let ennemies_in_range: Vec<&KBot> = candidates
+                                .iter()
+                                .filter(|potential_ennemy| is_ennemy_in_range(potential_ennemy))
+                                .map(|potential_ennemy| kbots.get(potential_ennemy).unwrap())
+                                .collect();
+                            let distance2 = |kbot: &&KBot| {
+                                (kbot.position.coords - me_kbot.position.coords).magnitude_squared()
+                            };
+                            let closest = || {
+                                ennemies_in_range
+                                    .iter()
+                                    .min_by(|a, b| distance2(a).partial_cmp(&distance2(b)).unwrap())
+                                    .map(|kbot| kbot.id)
+                            };
+
+                            match weapon.targeting {
+                                botdef::TargetingPolicy::Closest => closest(),
+                                botdef::TargetingPolicy::LowestLife => ennemies_in_range
+                                    .iter()
+                                    .min_by_key(|kbot| kbot.life)
+                                    .map(|kbot| kbot.id),
+                                botdef::TargetingPolicy::HighestMetalCost => ennemies_in_range
+                                    .iter()
+                                    .max_by_key(|kbot| {
+                                        bot_defs.get(&kbot.botdef_id).unwrap().metal_cost
+                                    })
+                                    .map(|kbot| kbot.id),
+                                botdef::TargetingPolicy::KeepTarget => weapon_state
+                                    .target
+                                    .filter(is_ennemy_in_range)
+                                    .or_else(closest),
                             }
+                        }),
+                    };
+
+                    if let Some(target) = target {
+                        let target_kbot = kbots.get(&target).unwrap();
+                        //Shoot from above the ground so that the slope we stand on doesn't block us
+                        let position_at_birth = me_kbot.position
+                            + me_kbot.up * botdef.radius
+                            + (target_kbot.position - me_kbot.position).normalize()
+                                * (botdef.radius + weapon.projectile_radius + 0.01);
+                        //Unreachable targets are not shot at, the weapon stays loaded
+                        if let Some(speed) = KinematicProjectile::launch_speed_to_intercept(
+                            position_at_birth,
+                            target_kbot.position,
+                            target_kbot.speed,
+                            weapon.muzzle_speed,
+                            Vector3::new(0.0, 0.0, -weapon.gravity),
+                            weapon.life_frame_count(),
+                        ) {
+                            shots.push(Shot {
+                                bot: *me,
+                                weapon_index,
+                                target_id: target,
+                                position_at_birth,
+                                speed,
+                            });
                         }
                     }
                 }
@@ -490,19 +1134,25 @@ pub fn update_units(
 
         for shot in shots.iter() {
             let kbot = kbots.get_mut(&shot.bot).unwrap();
-            let dir = (shot.target - kbot.position.coords).normalize();
 
-            kbot.weapon0_dir = dir;
-            kbot.frame_last_shot = frame_count;
-            let kbot_radius = bot_defs.get(&kbot.botdef_id).unwrap().radius;
+            let weapon_state = &mut kbot.weapons[shot.weapon_index];
+            weapon_state.dir = shot.speed.normalize();
+            weapon_state.frame_last_shot = frame_count;
+            weapon_state.target = Some(shot.target_id);
+            let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+            let weapon = &botdef.weapons[shot.weapon_index];
+            let life_frame_count = weapon.life_frame_count();
             let proj = KinematicProjectile {
                 id: rand_id(),
+                from: shot.bot,
+                botdef_id: botdef.id,
+                weapon_index: shot.weapon_index,
                 birth_frame: frame_count,
-                death_frame: frame_count + 6,
-                position_at_birth: kbot.position + dir * (kbot_radius + 0.25 + 0.01),
-                speed_per_frame_at_birth: dir * 2.0 + Vector3::new(0.0, 0.0, 0.2),
-                accel_per_frame: Vector3::new(0.0, 0.0, -0.08),
-                radius: 0.25,
+                death_frame: frame_count + life_frame_count,
+                position_at_birth: shot.position_at_birth,
+                speed_per_frame_at_birth: shot.speed,
+                accel_per_frame: Vector3::new(0.0, 0.0, -weapon.gravity),
+                radius: weapon.projectile_radius,
                 position_cache: Vec::new(),
                 speed_cache: Vec::new(),
             };
@@ -523,12 +1173,29 @@ pub fn update_units(
         to: Id<KBot>,
     }
     let mut build_throughputs = Vec::new();
+    struct ReclaimPart {
+        amount: f64,
+        player: Id<Player>,
+        target: Reclaimable,
+    }
+    let mut reclaim_throughputs = Vec::new();
     //Build compute
     for (id, mobile) in kbots.iter_mut() {
         if mobile.con_completed >= 1.0 {
-            // Look at current_command, change move_target if necessary
-            match mobile.current_command {
-                Command::Build(to_build) => match mobiles2.get(&to_build) {
+            // Look at the current command, change move_target if necessary
+            match mobile.commands.front() {
+                Some(&Command::Move { target, facing, .. }) => {
+                    let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+                    let to_target = (target.coords - (mobile.position.coords + mobile.speed)).xy();
+                    if to_target.norm() < botdef.radius {
+                        mobile.commands.pop_front();
+                        mobile.move_target = None;
+                        mobile.facing = facing;
+                    } else {
+                        mobile.move_target = Some(target);
+                    }
+                }
+                Some(&Command::Build(to_build)) => match mobiles2.get(&to_build) {
                     Some(to_build) => {
                         if to_build.con_completed < 1.0 {
                             let dist =
@@ -547,13 +1214,139 @@ pub fn update_units(
                                 mobile.move_target = Some(to_build.position);
                             }
                         } else {
-                            mobile.current_command = Command::None;
+                            mobile.commands.pop_front();
                             mobile.move_target = None;
                         }
                     }
-                    None => {}
+                    None => {
+                        mobile.commands.pop_front();
+                        mobile.move_target = None;
+                    }
+                },
+                Some(&Command::Attack(target)) => {
+                    let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+                    match (mobiles2.get(&target), botdef.max_weapon_range()) {
+                        (Some(target), Some(range)) => {
+                            let dist =
+                                (target.position.coords - mobile.position.coords).magnitude();
+                            if dist < range {
+                                mobile.move_target = None;
+                            } else {
+                                mobile.move_target = Some(target.position);
+                            }
+                        }
+                        _ => {
+                            mobile.commands.pop_front();
+                            mobile.move_target = None;
+                        }
+                    }
+                }
+                Some(&Command::Guard(ward)) => match mobiles2.get(&ward) {
+                    Some(ward) => {
+                        let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+                        //Only armed guards engage the attackers
+                        let attacker = match botdef.max_weapon_range() {
+                            Some(range) => ward
+                                .recent_attacker(frame_count, ATTACKER_MEMORY)
+                                .and_then(|attacker| mobiles2.get(&attacker))
+                                .filter(|attacker| attacker.team != mobile.team)
+                                .map(|attacker| (attacker, range)),
+                            None => None,
+                        };
+                        //Builders help with the ward's construction or repair, or with its factory production
+                        let assisted = if botdef.build_power > 0.0 {
+                            match ward.commands.front() {
+                                Some(Command::Build(assisted))
+                                | Some(Command::Repair(assisted)) => {
+                                    mobiles2.get(assisted).filter(|assisted| {
+                                        let assisted_botdef =
+                                            bot_defs.get(&assisted.botdef_id).unwrap();
+                                        assisted.con_completed < 1.0
+                                            || assisted.life < assisted_botdef.max_life
+                                    })
+                                }
+                                _ => None,
+                            }
+                            .or_else(|| {
+                                ward.factory_building
+                                    .and_then(|building| mobiles2.get(&building))
+                                    .filter(|building| {
+                                        building.con_completed < 1.0 && building.life > 0
+                                    })
+                            })
+                        } else {
+                            None
+                        };
+
+                        if let Some((attacker, range)) = attacker {
+                            let dist =
+                                (attacker.position.coords - mobile.position.coords).magnitude();
+                            if dist < range {
+                                mobile.move_target = None;
+                            } else {
+                                mobile.move_target = Some(attacker.position);
+                            }
+                        } else if let Some(to_build) = assisted {
+                            let dist =
+                                (to_build.position.coords - mobile.position.coords).magnitude();
+                            if dist <= botdef.build_dist {
+                                mobile.move_target = None;
+                                build_throughputs.push(BuildPart {
+                                    amount: botdef.build_power as f64,
+                                    repair: to_build.con_completed >= 1.0,
+                                    player: mobile.player_id,
+                                    from: *id,
+                                    to: to_build.id,
+                                })
+                            } else {
+                                mobile.move_target = Some(to_build.position);
+                            }
+                        } else {
+                            let dist = (ward.position.coords - mobile.position.coords).magnitude();
+                            if dist > GUARD_DISTANCE {
+                                mobile.move_target = Some(ward.position);
+                            } else {
+                                mobile.move_target = None;
+                            }
+                        }
+                    }
+                    None => {
+                        mobile.commands.pop_front();
+                        mobile.move_target = None;
+                    }
                 },
-                Command::Repair(to_build) => match mobiles2.get(&to_build) {
+                Some(&Command::Reclaim(target)) => {
+                    let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+                    let position = match target {
+                        Reclaimable::Feature(feature) => {
+                            features.get(&feature).map(|feature| feature.position)
+                        }
+                        Reclaimable::KBot(kbot) => mobiles2
+                            .get(&kbot)
+                            .filter(|kbot| kbot.team != mobile.team || kbot.con_completed < 1.0)
+                            .map(|kbot| kbot.position),
+                    };
+                    match position {
+                        Some(position) if botdef.build_power > 0.0 => {
+                            let dist = (position.coords - mobile.position.coords).magnitude();
+                            if dist <= botdef.build_dist {
+                                mobile.move_target = None;
+                                reclaim_throughputs.push(ReclaimPart {
+                                    amount: botdef.build_power as f64,
+                                    player: mobile.player_id,
+                                    target,
+                                })
+                            } else {
+                                mobile.move_target = Some(position);
+                            }
+                        }
+                        _ => {
+                            mobile.commands.pop_front();
+                            mobile.move_target = None;
+                        }
+                    }
+                }
+                Some(&Command::Repair(to_build)) => match mobiles2.get(&to_build) {
                     Some(to_build) => {
                         let botdef_of_to_build = bot_defs.get(&to_build.botdef_id).unwrap();
                         if to_build.life < botdef_of_to_build.max_life
@@ -575,13 +1368,202 @@ pub fn update_units(
                                 mobile.move_target = Some(to_build.position);
                             }
                         } else {
-                            mobile.current_command = Command::None;
+                            mobile.commands.pop_front();
                             mobile.move_target = None;
                         }
                     }
-                    None => {}
+                    None => {
+                        mobile.commands.pop_front();
+                        mobile.move_target = None;
+                    }
                 },
-                _ => {}
+                None => {}
+            }
+        }
+    }
+
+    //Factories build the front of their queue in place
+    {
+        let mut births = Vec::new();
+        let mut leaving = Vec::new();
+        for (id, factory) in kbots.iter_mut() {
+            let botdef = bot_defs.get(&factory.botdef_id).unwrap();
+            if botdef.is_factory && factory.con_completed >= 1.0 {
+                match factory
+                    .factory_building
+                    .and_then(|building| mobiles2.get(&building))
+                    .filter(|building| building.life > 0)
+                {
+                    Some(building) if building.con_completed < 1.0 => {
+                        build_throughputs.push(BuildPart {
+                            amount: botdef.build_power as f64,
+                            repair: false,
+                            player: factory.player_id,
+                            from: *id,
+                            to: building.id,
+                        });
+                    }
+                    Some(building) => {
+                        let exit = factory.position + Vector3::new(0.0, botdef.footprint.y, 0.0);
+                        leaving.push((building.id, factory.rally_point.unwrap_or(exit)));
+                        factory.factory_building = None;
+                        factory.factory_queue.pop_one();
+                    }
+                    //Nothing in construction, or it was destroyed or cancelled
+                    None => {
+                        factory.factory_building = None;
+                        if let Some(entry) = factory.factory_queue.entries.front() {
+                            let unit_botdef = bot_defs.get(&entry.botdef_id).unwrap();
+                            let mut m = KBot::new(factory.position, unit_botdef, factory.player_id);
+                            m.team = factory.team;
+                            m.con_completed = std::f32::MIN_POSITIVE;
+                            m.life = 1;
+                            factory.factory_building = Some(m.id);
+                            births.push(m);
+                        }
+                    }
+                }
+            }
+        }
+
+        for m in births {
+            players.get_mut(&m.player_id).unwrap().kbots.insert(m.id);
+            kbots.insert(m.id, m);
+        }
+        for (unit, rally_point) in leaving {
+            if let Some(unit) = kbots.get_mut(&unit) {
+                unit.push_command(
+                    Command::Move {
+                        target: rally_point,
+                        facing: None,
+                        speed: None,
+                    },
+                    false,
+                );
+            }
+        }
+    }
+
+    //Cloaked kbots are revealed by enemies in their decloak radius and when they fire
+    let decloaked: FnvHashSet<Id<KBot>> = kbots
+        .values()
+        .filter(|kbot| kbot.cloak_wanted)
+        .filter(|kbot| {
+            let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+            let fired = kbot
+                .weapons
+                .iter()
+                .any(|weapon| frame_count - weapon.frame_last_shot < DECLOAK_FRAME_COUNT);
+            fired
+                || index_aabb(
+                    kbot.position.coords,
+                    botdef.decloak_radius,
+                    cell_size,
+                    grid_w,
+                    grid_h,
+                )
+                .iter()
+                .any(|index| {
+                    small_grid[*index].iter().any(|other_id| {
+                        kbots.get(other_id).map_or(false, |other| {
+                            other.team != kbot.team
+                                && other.con_completed >= 1.0
+                                && (other.position - kbot.position).magnitude()
+                                    < botdef.decloak_radius
+                        })
+                    })
+                })
+        })
+        .map(|kbot| kbot.id)
+        .collect();
+
+    //Income of the frame: metal extraction and energy production, minus upkeep
+    for player in players.values_mut() {
+        player.metal_income = 0.0;
+        player.metal_expense = 0.0;
+        player.energy_income = 0.0;
+        player.energy_expense = 0.0;
+    }
+    //Reclaimed metal is part of the income
+    for ReclaimPart {
+        amount,
+        player,
+        target,
+    } in reclaim_throughputs
+    {
+        let reclaimed = match target {
+            Reclaimable::Feature(feature) => match features.get_mut(&feature) {
+                Some(feature) => {
+                    let reclaimed = amount.min(feature.metal as f64);
+                    feature.metal -= reclaimed as f32;
+                    reclaimed
+                }
+                None => 0.0,
+            },
+            //Reclaiming a kbot undoes its construction
+            Reclaimable::KBot(kbot) => match kbots.get_mut(&kbot) {
+                Some(kbot) => {
+                    let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+                    let metal_built = kbot.con_completed as f64 * botdef.metal_cost as f64;
+                    let reclaimed = amount.min(metal_built);
+                    kbot.con_completed =
+                        ((metal_built - reclaimed) / botdef.metal_cost as f64) as f32;
+                    kbot.life = kbot
+                        .life
+                        .min((botdef.max_life as f32 * kbot.con_completed).ceil() as i32);
+                    reclaimed
+                }
+                None => 0.0,
+            },
+        };
+        let player = players.get_mut(&player).unwrap();
+        player.metal += reclaimed;
+        player.metal_income += reclaimed;
+    }
+    features.retain(|_, feature| feature.metal > 0.0);
+
+    {
+        //Each spot is mined by a single extractor
+        let mut spot_mined = vec![false; heightmap_phy.data.metal_spots.len()];
+        for kbot in kbots.values_mut() {
+            if kbot.con_completed >= 1.0 {
+                let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+                let player = players.get_mut(&kbot.player_id).unwrap();
+                if botdef.extractor {
+                    for (spot, mined) in heightmap_phy
+                        .data
+                        .metal_spots
+                        .iter()
+                        .zip(spot_mined.iter_mut())
+                    {
+                        let offset =
+                            Vector2::new(spot.x as f32, spot.y as f32) - kbot.position.coords.xy();
+                        let in_footprint = offset.x.abs() <= botdef.footprint.x / 2.0
+                            && offset.y.abs() <= botdef.footprint.y / 2.0;
+                        if !*mined && in_footprint {
+                            *mined = true;
+                            player.metal += spot.metal_per_frame as f64;
+                            player.metal_income += spot.metal_per_frame as f64;
+                        }
+                    }
+                }
+
+                player.energy += botdef.energy_production as f64;
+                player.energy_income += botdef.energy_production as f64;
+                //Upkeep is only paid with what is in stock
+                let upkeep = (botdef.energy_upkeep as f64).min(player.energy);
+                player.energy -= upkeep;
+                player.energy_expense += upkeep;
+
+                //Without enough energy in stock the cloak fails
+                kbot.cloaked = botdef.cloak
+                    && kbot.cloak_wanted
+                    && !decloaked.contains(&kbot.id)
+                    && player.energy >= botdef.cloak_upkeep as f64;
+                if kbot.cloaked {
+                    player.energy -= botdef.cloak_upkeep as f64;
+                    player.energy_expense += botdef.cloak_upkeep as f64;
+                }
             }
         }
     }
@@ -601,16 +1583,24 @@ pub fn update_units(
         player,
     } in build_throughputs.iter()
     {
+        let botdef = bot_defs.get(&kbots.get(to).unwrap().botdef_id).unwrap();
+        let energy_per_metal = botdef.energy_cost as f64 / botdef.metal_cost as f64;
         let stat = resources_usage.entry(*player).or_insert(ResourceUsage {
             metal: 0.0,
             energy: 0.0,
         });
         *stat = ResourceUsage {
             metal: stat.metal + if *repair { 0.0 } else { *amount as f64 },
-            energy: 0.0,
+            energy: stat.energy
+                + if *repair {
+                    0.0
+                } else {
+                    *amount as f64 * energy_per_metal
+                },
         };
     }
     //Compute what proportion of usage is usable without negative stock
+    //Both resources are drawn in proportion, a shortage of either one slows every construction
     struct ResourceUsagePropMax {
         metal: f64,
         energy: f64,
@@ -618,25 +1608,31 @@ pub fn update_units(
     let mut usage_props_max = FnvHashMap::<Id<Player>, ResourceUsagePropMax>::default();
     for (player_id, player) in players.iter_mut() {
         if let Some(ru) = resources_usage.get(player_id) {
-            let current_metal_stock = player.metal;
-            let current_energy_stock = player.energy;
+            let prop_max = |stock: f64, needed: f64| {
+                if needed > 0.0 {
+                    (stock / needed).min(1.0)
+                } else {
+                    1.0
+                }
+            };
 
             let metal_needed = ru.metal;
             let energy_needed = ru.energy;
 
-            //TODO Energy count too
-            let metal_prop_max: f64 = (current_metal_stock / metal_needed)
-                .min(current_energy_stock / energy_needed)
-                .min(1.0);
+            let usage_prop_max =
+                prop_max(player.metal, metal_needed).min(prop_max(player.energy, energy_needed));
             usage_props_max.insert(
                 *player_id,
                 ResourceUsagePropMax {
-                    metal: metal_prop_max,
-                    energy: 1.0,
+                    metal: usage_prop_max,
+                    energy: usage_prop_max,
                 },
             );
 
-            player.metal = (player.metal - metal_needed * metal_prop_max).max(0.0);
+            player.metal = (player.metal - metal_needed * usage_prop_max).max(0.0);
+            player.energy = (player.energy - energy_needed * usage_prop_max).max(0.0);
+            player.metal_expense += metal_needed * usage_prop_max;
+            player.energy_expense += energy_needed * usage_prop_max;
         }
     }
 
@@ -674,7 +1670,13 @@ pub fn update_units(
             let metal_not_used = metal_available - metal_needed;
             metal_used = metal_available - metal_not_used;
             if !repair {
-                players.get_mut(&player).unwrap().metal += metal_not_used;
+                let energy_not_used =
+                    metal_not_used * botdef.energy_cost as f64 / botdef.metal_cost as f64;
+                let player = players.get_mut(&player).unwrap();
+                player.metal += metal_not_used;
+                player.metal_expense -= metal_not_used;
+                player.energy += energy_not_used;
+                player.energy_expense -= energy_not_used;
                 kbot.con_completed = 1.0;
             }
         }
@@ -687,14 +1689,171 @@ pub fn update_units(
             .min((botdef.max_life as f32 * kbot.con_completed).ceil() as i32);
     }
 
+    //Storage caps, excess flows to the allies with room left or is wasted
+    {
+        for player in players.values_mut() {
+            player.metal_storage = BASE_METAL_STORAGE;
+            player.energy_storage = BASE_ENERGY_STORAGE;
+        }
+        for kbot in kbots.values() {
+            if kbot.con_completed >= 1.0 {
+                let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+                let player = players.get_mut(&kbot.player_id).unwrap();
+                player.metal_storage += botdef.metal_storage as f64;
+                player.energy_storage += botdef.energy_storage as f64;
+            }
+        }
+
+        let mut player_ids: Vec<Id<Player>> = players.keys().copied().collect();
+        player_ids.sort_by_key(|id| id.value);
+        for id in player_ids.iter() {
+            let player = players.get_mut(id).unwrap();
+            let team = player.team;
+            let mut metal_excess = (player.metal - player.metal_storage).max(0.0);
+            let mut energy_excess = (player.energy - player.energy_storage).max(0.0);
+            player.metal -= metal_excess;
+            player.energy -= energy_excess;
+
+            for ally_id in player_ids.iter().filter(|ally_id| *ally_id != id) {
+                let ally = players.get_mut(ally_id).unwrap();
+                if ally.team == team {
+                    let metal_given = metal_excess.min((ally.metal_storage - ally.metal).max(0.0));
+                    let energy_given =
+                        energy_excess.min((ally.energy_storage - ally.energy).max(0.0));
+                    ally.metal += metal_given;
+                    ally.energy += energy_given;
+                    metal_excess -= metal_given;
+                    energy_excess -= energy_given;
+                }
+            }
+        }
+    }
+
     frame_profiler.add("01b build compute", start.elapsed());
 
+    //Count units per movement class and destination region
+    let mut flow_field_users = FnvHashMap::default();
+    for mobile in kbots.values() {
+        let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+        if let Some(destination) = mobile.move_target {
+            if mobile.con_completed >= 1.0 && !botdef.is_structure {
+                let region = pathfinding::region_of(destination.coords.xy());
+                *flow_field_users
+                    .entry((botdef.movement_class.passability_key(), region))
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+
+    //Follow waypoints toward the destination chosen by the commands
+    for mobile in kbots.values_mut() {
+        let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+        match mobile.move_target {
+            Some(destination) if mobile.con_completed >= 1.0 && !botdef.is_structure => {
+                //Large groups follow a flow field until they reach the destination region
+                let region = pathfinding::region_of(destination.coords.xy());
+                if flow_field_users[&(botdef.movement_class.passability_key(), region)]
+                    >= FLOW_FIELD_MIN_UNITS
+                    && pathfinding::region_of(mobile.position.coords.xy()) != region
+                {
+                    mobile.path.clear();
+                    mobile.path_destination = None;
+                    match pathfinding.sample_flow_field(
+                        heightmap_phy,
+                        &botdef.movement_class,
+                        region,
+                        mobile.position,
+                        frame_count,
+                    ) {
+                        Some(waypoint) => mobile.move_target = Some(waypoint),
+                        //Unreachable region, stop instead of searching a path for every unit
+                        None => {
+                            if let Some(&Command::Move { facing, .. }) = mobile.commands.front() {
+                                mobile.commands.pop_front();
+                                mobile.facing = facing;
+                            }
+                            mobile.move_target = None;
+                        }
+                    }
+                    continue;
+                }
+
+                //A fixed destination needs a path of its own, a moving one only once it moved enough
+                let repath_distance = match mobile.commands.front() {
+                    Some(Command::Move { .. })
+                    | Some(Command::Build(_))
+                    | Some(Command::Reclaim(Reclaimable::Feature(_))) => 0.0,
+                    _ => REPATH_DISTANCE,
+                };
+                let repath = match mobile.path_destination {
+                    Some(path_destination) => {
+                        mobile.path.is_empty()
+                            || (path_destination.coords - destination.coords)
+                                .xy()
+                                .magnitude()
+                                > repath_distance
+                    }
+                    None => true,
+                };
+                if repath {
+                    mobile.path = pathfinding.find_path(
+                        heightmap_phy,
+                        &botdef.movement_class,
+                        mobile.position,
+                        destination,
+                    );
+                    mobile.path_destination = Some(destination);
+                }
+                while mobile.path.len() > 1
+                    && (mobile.path[0].coords - mobile.position.coords)
+                        .xy()
+                        .magnitude()
+                        < WAYPOINT_REACHED_DISTANCE
+                {
+                    mobile.path.remove(0);
+                }
+
+                //The path ends short of an unreachable destination, stop there
+                let end_reached = mobile.path.len() == 1
+                    && (mobile.path[0].coords - mobile.position.coords)
+                        .xy()
+                        .magnitude()
+                        < WAYPOINT_REACHED_DISTANCE
+                    && (mobile.path[0].coords - destination.coords)
+                        .xy()
+                        .magnitude()
+                        > WAYPOINT_REACHED_DISTANCE;
+                if end_reached {
+                    if let Some(&Command::Move { facing, .. }) = mobile.commands.front() {
+                        mobile.commands.pop_front();
+                        mobile.facing = facing;
+                    }
+                    mobile.move_target = None;
+                } else {
+                    mobile.move_target = mobile.path.first().copied();
+                }
+            }
+            _ => {
+                mobile.path.clear();
+                mobile.path_destination = None;
+            }
+        }
+    }
+    pathfinding.evict_flow_fields(frame_count);
+
     //Movement compute
 
     for (id, mobile) in kbots.iter_mut() {
         if mobile.con_completed >= 1.0 {
+            //Structures never move
+            if bot_defs.get(&mobile.botdef_id).unwrap().is_structure {
+                mobile.move_target = None;
+                mobile.speed = Vector3::new(0.0, 0.0, 0.0);
+                continue;
+            }
             if mobile.speed.magnitude_squared() > 0.001
                 || mobile.move_target.is_some()
+                || mobile.facing.is_some()
                 || !mobile.grounded
             {
                 let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
@@ -727,14 +1886,20 @@ pub fn update_units(
                 if stop_tracking {
                     mobile.move_target = None;
                 }
+                if mobile.move_target.is_some() {
+                    mobile.facing = None;
+                }
 
                 let dir = avoidance_force + target_force;
                 let dir_intensity = (avoidance_force.norm() + target_force.norm())
                     .max(0.0)
                     .min(1.0);
 
-                //Clamp in cone
-                let wanted_angle: Angle = dir.into();
+                //Clamp in cone, once stopped turn to the facing of the order
+                let wanted_angle: Angle = match mobile.facing {
+                    Some(facing) => facing,
+                    None => dir.into(),
+                };
                 let current_angle = mobile.angle;
 
                 fn clamp_abs(x: f32, max_abs: f32) -> f32 {
@@ -752,6 +1917,11 @@ pub fn update_units(
                 let new_angle = current_angle + mobile.angular_velocity.into();
                 // current_angle.clamp_around(wanted_angle, mobile.angular_velocity.into());
                 mobile.angle = new_angle;
+                if let Some(facing) = mobile.facing {
+                    if (facing - new_angle).rad.abs() < FACING_TOLERANCE {
+                        mobile.facing = None;
+                    }
+                }
                 let new_dir: Vector2<f32> = new_angle.into();
                 mobile.dir = Vector3::new(new_dir.x, new_dir.y, 0.0);
 
@@ -789,31 +1959,60 @@ pub fn update_units(
 
                 mobile.speed = mobile.dir * (accel + mobile.speed.magnitude()).max(0.0);
 
+                //Slower on slopes, water is flat for hovers
+                let movement_class = &botdef.movement_class;
+                let floating = movement_class.hover
+                    && heightmap_phy.water_depth(mobile.position.x, mobile.position.y) > 0.0;
+                let slope = if floating { 0.0 } else { mobile.up.z.acos() };
+                let max_speed = botdef.max_speed * movement_class.speed_modifier(slope);
+                //Formations move at the speed of their slowest unit
+                let max_speed = match mobile.commands.front() {
+                    Some(Command::Move {
+                        speed: Some(speed), ..
+                    }) => max_speed.min(*speed),
+                    _ => max_speed,
+                };
                 let speed = mobile.speed.magnitude();
-                if speed > botdef.max_speed {
-                    mobile.speed /= speed / botdef.max_speed;
+                if speed > max_speed {
+                    mobile.speed /= speed / max_speed;
                 }
 
-                mobile.position += mobile.speed;
-                mobile.position.x = mobile
-                    .position
-                    .x
+                let next_x = (mobile.position.x + mobile.speed.x)
                     .max(0.0)
                     .min(heightmap_phy.width as f32 - 1.0);
-                mobile.position.y = mobile
-                    .position
-                    .y
+                let next_y = (mobile.position.y + mobile.speed.y)
                     .max(0.0)
                     .min(heightmap_phy.height as f32 - 1.0);
+                //Stop before ground the movement class can't cross, kbots already on such ground may leave it
+                if can_stand(heightmap_phy, movement_class, next_x, next_y)
+                    || !can_stand(
+                        heightmap_phy,
+                        movement_class,
+                        mobile.position.x,
+                        mobile.position.y,
+                    )
+                {
+                    mobile.position.x = next_x;
+                    mobile.position.y = next_y;
+                } else {
+                    mobile.speed = Vector3::new(0.0, 0.0, 0.0);
+                }
                 mobile.position.z = heightmap_phy.z_linear(mobile.position.x, mobile.position.y);
                 mobile.grounded = true;
                 mobile.up = heightmap_phy.normal(mobile.position.x, mobile.position.y);
+                //Hovers sit on the water surface, the others walk on the bottom
+                if movement_class.hover && mobile.position.z < heightmap_phy.data.water_level {
+                    mobile.position.z = heightmap_phy.data.water_level;
+                    mobile.up = Vector3::new(0.0, 0.0, 1.0);
+                }
 
                 let y = -mobile.dir.cross(&mobile.up);
                 let x = y.cross(&mobile.up);
                 mobile.dir = x;
 
-                mobile.weapon0_dir = (mobile.weapon0_dir + mobile.dir).normalize();
+                for weapon in mobile.weapons.iter_mut() {
+                    weapon.dir = (weapon.dir + mobile.dir).normalize();
+                }
                 //w = v/r
                 mobile.wheel0_angle += mobile.speed.norm() / 0.5;
             }
@@ -821,11 +2020,106 @@ pub fn update_units(
     }
     frame_profiler.add("02  movement", start.elapsed());
 
-    //Remove dead kbot
+    //Hard collisions, overlapping kbots are pushed apart in proportion to the mass of the other
+    let start = std::time::Instant::now();
+    let is_pushable =
+        |kbot: &KBot, botdef: &botdef::BotDef| kbot.con_completed >= 1.0 && !botdef.is_structure;
+    let mut pushes = Vec::new();
+    for (id, kbot) in kbots.iter() {
+        let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+        if !is_pushable(kbot, botdef) {
+            continue;
+        }
+
+        let mut neighbors = FnvHashSet::default();
+        for index in index_aabb(
+            kbot.position.coords,
+            botdef.radius,
+            cell_size,
+            grid_w,
+            grid_h,
+        ) {
+            neighbors.extend(small_grid[index].iter().filter(|other_id| *other_id != id));
+        }
+
+        let mut push = Vector2::new(0.0, 0.0);
+        for other_id in neighbors {
+            //small_grid was filled before births
+            if let Some(other) = kbots.get(&other_id) {
+                let other_botdef = bot_defs.get(&other.botdef_id).unwrap();
+                let to_me = (kbot.position.coords - other.position.coords).xy();
+                let distance = to_me.magnitude();
+                let overlap = botdef.radius + other_botdef.radius - distance;
+                if overlap <= 0.0 {
+                    continue;
+                }
+                //Units on the same spot are separated along an arbitrary axis
+                let normal = if distance > 0.001 {
+                    to_me / distance
+                } else if id.value < other_id.value {
+                    Vector2::new(1.0, 0.0)
+                } else {
+                    Vector2::new(-1.0, 0.0)
+                };
+                let share = if is_pushable(other, other_botdef) {
+                    other_botdef.mass / (botdef.mass + other_botdef.mass)
+                } else {
+                    1.0
+                };
+                push += normal * overlap * share;
+            }
+        }
+        if push != Vector2::new(0.0, 0.0) {
+            pushes.push((*id, push));
+        }
+    }
+    for (id, push) in pushes {
+        let kbot = kbots.get_mut(&id).unwrap();
+        let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+        let next_x = (kbot.position.x + push.x)
+            .max(0.0)
+            .min(heightmap_phy.width as f32 - 1.0);
+        let next_y = (kbot.position.y + push.y)
+            .max(0.0)
+            .min(heightmap_phy.height as f32 - 1.0);
+        //Never pushed onto ground its movement class can't cross
+        if !can_stand(heightmap_phy, &botdef.movement_class, next_x, next_y)
+            && can_stand(
+                heightmap_phy,
+                &botdef.movement_class,
+                kbot.position.x,
+                kbot.position.y,
+            )
+        {
+            continue;
+        }
+        kbot.position.x = next_x;
+        kbot.position.y = next_y;
+        kbot.position.z = heightmap_phy.z_linear(kbot.position.x, kbot.position.y);
+        if botdef.movement_class.hover {
+            kbot.position.z = kbot.position.z.max(heightmap_phy.data.water_level);
+        }
+    }
+    frame_profiler.add("02b collision", start.elapsed());
+
+    //Remove dead kbot, completed ones leave a wreck
     for (id, kbot) in kbots.iter() {
         if kbot.life <= 0 {
             kbots_dead.insert(*id);
 
+            if kbot.con_completed >= 1.0 {
+                let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+                let feature = Feature {
+                    id: rand_id(),
+                    position: kbot.position,
+                    dir: kbot.dir,
+                    up: kbot.up,
+                    metal: botdef.metal_cost as f32 * WRECK_METAL_RATIO,
+                    botdef_id: kbot.botdef_id,
+                };
+                features.insert(feature.id, feature);
+            }
+
             explosions.push(ExplosionEvent {
                 position: Point3::from(kbot.position),
                 size: 1.0,
@@ -839,6 +2133,17 @@ pub fn update_units(
     }
 }
 
+///The movement class can stand on the ground at x, y
+fn can_stand(
+    heightmap_phy: &heightmap_phy::HeightmapPhy,
+    movement_class: &botdef::MovementClass,
+    x: f32,
+    y: f32,
+) -> bool {
+    let slope = heightmap_phy.normal(x, y).z.acos();
+    movement_class.can_cross(slope, heightmap_phy.water_depth(x, y))
+}
+
 fn avoid_neighbors_force(
     me: &KBot,
     neighbors_id: Vec<Id<KBot>>,
@@ -0,0 +1,455 @@
+use crate::botdef::MovementClass;
+use crate::heightmap_phy::HeightmapPhy;
+use fnv::FnvHashMap;
+use na::{Point3, Vector2};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+///m, side of a cell of the passability grids
+pub const CELL_SIZE: f32 = 4.0;
+///m, side of the square areas of the map flow fields lead to
+pub const FLOW_FIELD_REGION_SIZE: f32 = 32.0;
+
+const NEIGHBORS: [(i32, i32); 8] = [
+    (-1, 0),
+    (1, 0),
+    (0, -1),
+    (0, 1),
+    (-1, -1),
+    (1, -1),
+    (-1, 1),
+    (1, 1),
+];
+
+pub fn region_of(position: Vector2<f32>) -> (i32, i32) {
+    (
+        (position.x / FLOW_FIELD_REGION_SIZE).floor() as i32,
+        (position.y / FLOW_FIELD_REGION_SIZE).floor() as i32,
+    )
+}
+
+///Cells of the map a movement class can cross
+pub struct PassabilityGrid {
+    pub width: usize,
+    pub height: usize,
+    pub passable: Vec<bool>,
+}
+
+impl PassabilityGrid {
+    pub fn new(heightmap_phy: &HeightmapPhy, movement_class: &MovementClass) -> Self {
+        let width = (heightmap_phy.width as f32 / CELL_SIZE).ceil() as usize;
+        let height = (heightmap_phy.height as f32 / CELL_SIZE).ceil() as usize;
+        let mut passable = Vec::with_capacity(width * height);
+        for j in 0..height {
+            for i in 0..width {
+                let x = i as f32 * CELL_SIZE;
+                let y = j as f32 * CELL_SIZE;
+                //Corners and center of the cell
+                let samples = [
+                    (x, y),
+                    (x + CELL_SIZE, y),
+                    (x, y + CELL_SIZE),
+                    (x + CELL_SIZE, y + CELL_SIZE),
+                    (x + CELL_SIZE / 2.0, y + CELL_SIZE / 2.0),
+                ];
+                passable.push(samples.iter().all(|(x, y)| {
+                    let slope = heightmap_phy.normal(*x, *y).z.acos();
+                    movement_class.can_cross(slope, heightmap_phy.water_depth(*x, *y))
+                }));
+            }
+        }
+        PassabilityGrid {
+            width,
+            height,
+            passable,
+        }
+    }
+
+    pub fn cell_of(&self, position: Vector2<f32>) -> (usize, usize) {
+        let i = (position.x / CELL_SIZE).max(0.0) as usize;
+        let j = (position.y / CELL_SIZE).max(0.0) as usize;
+        (i.min(self.width - 1), j.min(self.height - 1))
+    }
+
+    pub fn center_of(&self, (i, j): (usize, usize)) -> Vector2<f32> {
+        Vector2::new((i as f32 + 0.5) * CELL_SIZE, (j as f32 + 0.5) * CELL_SIZE)
+    }
+
+    pub fn is_passable(&self, (i, j): (usize, usize)) -> bool {
+        self.passable[i + j * self.width]
+    }
+
+    ///Neighbor cell reachable from (i, j) in direction (di, dj), without cutting corners
+    fn step(&self, (i, j): (usize, usize), (di, dj): (i32, i32)) -> Option<(usize, usize)> {
+        let ni = i as i32 + di;
+        let nj = j as i32 + dj;
+        if ni < 0 || nj < 0 || ni >= self.width as i32 || nj >= self.height as i32 {
+            return None;
+        }
+        let neighbor = (ni as usize, nj as usize);
+        if !self.is_passable(neighbor) {
+            return None;
+        }
+        if di != 0
+            && dj != 0
+            && !(self.is_passable((ni as usize, j)) && self.is_passable((i, nj as usize)))
+        {
+            return None;
+        }
+        Some(neighbor)
+    }
+
+    ///Every cell crossed by the segment is passable
+    pub fn line_of_sight(&self, from: Vector2<f32>, to: Vector2<f32>) -> bool {
+        let step_count = ((to - from).magnitude() / (CELL_SIZE / 2.0)).ceil() as usize;
+        (0..=step_count).all(|step| {
+            let lambda = step as f32 / step_count.max(1) as f32;
+            self.is_passable(self.cell_of(from + (to - from) * lambda))
+        })
+    }
+}
+
+#[derive(PartialEq)]
+struct OpenNode {
+    estimated_cost: f32,
+    index: usize,
+}
+
+impl Eq for OpenNode {}
+
+impl Ord for OpenNode {
+    //Reversed to have a min heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .estimated_cost
+            .partial_cmp(&self.estimated_cost)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+///Next cell to go to from every cell of the map to reach a region, shared by all the units going there
+pub struct FlowField {
+    ///Index of the next cell, None in the region and where it can't be reached
+    pub next: Vec<Option<usize>>,
+    pub last_used_frame: i32,
+}
+
+impl FlowField {
+    ///Dijkstra from every passable cell of the region
+    pub fn new(grid: &PassabilityGrid, region: (i32, i32)) -> Self {
+        let cell_count = grid.width * grid.height;
+        let mut cost = vec![std::f32::INFINITY; cell_count];
+        let mut next = vec![None; cell_count];
+        let mut open = BinaryHeap::new();
+
+        let cells_per_region = (FLOW_FIELD_REGION_SIZE / CELL_SIZE) as i32;
+        for j in region.1 * cells_per_region..(region.1 + 1) * cells_per_region {
+            for i in region.0 * cells_per_region..(region.0 + 1) * cells_per_region {
+                if i < 0 || j < 0 || i >= grid.width as i32 || j >= grid.height as i32 {
+                    continue;
+                }
+                if grid.is_passable((i as usize, j as usize)) {
+                    let index = i as usize + j as usize * grid.width;
+                    cost[index] = 0.0;
+                    open.push(OpenNode {
+                        estimated_cost: 0.0,
+                        index,
+                    });
+                }
+            }
+        }
+
+        while let Some(OpenNode {
+            estimated_cost,
+            index: current,
+        }) = open.pop()
+        {
+            //Outdated entry of the heap
+            if estimated_cost > cost[current] {
+                continue;
+            }
+            let (i, j) = (current % grid.width, current / grid.width);
+            for &(di, dj) in NEIGHBORS.iter() {
+                //Moves are symmetric, so the neighbor can step back to current
+                let neighbor = match grid.step((i, j), (di, dj)) {
+                    Some(neighbor) => neighbor,
+                    None => continue,
+                };
+                let neighbor = neighbor.0 + neighbor.1 * grid.width;
+                let step_cost = if di != 0 && dj != 0 {
+                    std::f32::consts::SQRT_2
+                } else {
+                    1.0
+                };
+                let new_cost = cost[current] + step_cost;
+                if new_cost < cost[neighbor] {
+                    cost[neighbor] = new_cost;
+                    next[neighbor] = Some(current);
+                    open.push(OpenNode {
+                        estimated_cost: new_cost,
+                        index: neighbor,
+                    });
+                }
+            }
+        }
+
+        FlowField {
+            next,
+            last_used_frame: 0,
+        }
+    }
+}
+
+///A* search and flow fields over passability grids, the grids are cached per movement class
+pub struct Pathfinding {
+    ///Key is MovementClass::passability_key
+    grids: FnvHashMap<(u32, u32, u32, bool), PassabilityGrid>,
+    ///Key is MovementClass::passability_key and the region
+    flow_fields: FnvHashMap<((u32, u32, u32, bool), (i32, i32)), FlowField>,
+}
+
+impl Pathfinding {
+    pub fn new() -> Self {
+        Pathfinding {
+            grids: FnvHashMap::default(),
+            flow_fields: FnvHashMap::default(),
+        }
+    }
+
+    ///To call when the heightmap changes
+    pub fn clear(&mut self) {
+        self.grids.clear();
+        self.flow_fields.clear();
+    }
+
+    ///Center of the next cell toward the region, a kbot on an impassable cell goes through a neighbor cell.
+    ///None when position is in the region, or when the region can't be reached from it:
+    ///the flow field caches this for all the units of the movement class.
+    pub fn sample_flow_field(
+        &mut self,
+        heightmap_phy: &HeightmapPhy,
+        movement_class: &MovementClass,
+        region: (i32, i32),
+        position: Point3<f32>,
+        frame_count: i32,
+    ) -> Option<Point3<f32>> {
+        self.passability(heightmap_phy, movement_class);
+        let grid = &self.grids[&movement_class.passability_key()];
+        let flow_field = self
+            .flow_fields
+            .entry((movement_class.passability_key(), region))
+            .or_insert_with(|| FlowField::new(grid, region));
+        flow_field.last_used_frame = frame_count;
+
+        let (i, j) = grid.cell_of(position.coords.xy());
+        let next = flow_field.next[i + j * grid.width].or_else(|| {
+            if grid.is_passable((i, j)) {
+                return None;
+            }
+            NEIGHBORS.iter().find_map(|&(di, dj)| {
+                let ni = i as i32 + di;
+                let nj = j as i32 + dj;
+                if ni < 0 || nj < 0 || ni >= grid.width as i32 || nj >= grid.height as i32 {
+                    return None;
+                }
+                let neighbor = ni as usize + nj as usize * grid.width;
+                flow_field.next[neighbor].map(|_| neighbor)
+            })
+        });
+        next.map(|next| {
+            let center = grid.center_of((next % grid.width, next / grid.width));
+            Point3::new(
+                center.x,
+                center.y,
+                heightmap_phy.z_linear(center.x, center.y),
+            )
+        })
+    }
+
+    ///Drop the flow fields no unit sampled this frame
+    pub fn evict_flow_fields(&mut self, frame_count: i32) {
+        self.flow_fields
+            .retain(|_, flow_field| flow_field.last_used_frame == frame_count);
+    }
+
+    pub fn passability(
+        &mut self,
+        heightmap_phy: &HeightmapPhy,
+        movement_class: &MovementClass,
+    ) -> &PassabilityGrid {
+        self.grids
+            .entry(movement_class.passability_key())
+            .or_insert_with(|| PassabilityGrid::new(heightmap_phy, movement_class))
+    }
+
+    ///Waypoints from start to goal, goal being the last one.
+    ///When the goal can't be reached the path ends at the center of the closest reachable cell.
+    pub fn find_path(
+        &mut self,
+        heightmap_phy: &HeightmapPhy,
+        movement_class: &MovementClass,
+        start: Point3<f32>,
+        goal: Point3<f32>,
+    ) -> Vec<Point3<f32>> {
+        let grid = self.passability(heightmap_phy, movement_class);
+
+        let start_cell = grid.cell_of(start.coords.xy());
+        let goal_cell = grid.cell_of(goal.coords.xy());
+        let index = |(i, j): (usize, usize)| i + j * grid.width;
+        let cell = |index: usize| (index % grid.width, index / grid.width);
+        //Octile distance
+        let heuristic = |(i, j): (usize, usize)| {
+            let dx = (i as f32 - goal_cell.0 as f32).abs();
+            let dy = (j as f32 - goal_cell.1 as f32).abs();
+            dx.max(dy) + (std::f32::consts::SQRT_2 - 1.0) * dx.min(dy)
+        };
+
+        let mut cost = vec![std::f32::INFINITY; grid.width * grid.height];
+        let mut came_from = vec![std::usize::MAX; grid.width * grid.height];
+        let mut open = BinaryHeap::new();
+
+        cost[index(start_cell)] = 0.0;
+        open.push(OpenNode {
+            estimated_cost: heuristic(start_cell),
+            index: index(start_cell),
+        });
+        let mut closest = index(start_cell);
+
+        while let Some(OpenNode {
+            estimated_cost,
+            index: current,
+        }) = open.pop()
+        {
+            let (i, j) = cell(current);
+            if heuristic((i, j)) < heuristic(cell(closest)) {
+                closest = current;
+            }
+            if current == index(goal_cell) {
+                break;
+            }
+            //Outdated entry of the heap
+            if estimated_cost > cost[current] + heuristic((i, j)) {
+                continue;
+            }
+
+            for &(di, dj) in NEIGHBORS.iter() {
+                let neighbor = match grid.step((i, j), (di, dj)) {
+                    Some(neighbor) => neighbor,
+                    None => continue,
+                };
+
+                let step_cost = if di != 0 && dj != 0 {
+                    std::f32::consts::SQRT_2
+                } else {
+                    1.0
+                };
+                let new_cost = cost[current] + step_cost;
+                if new_cost < cost[index(neighbor)] {
+                    cost[index(neighbor)] = new_cost;
+                    came_from[index(neighbor)] = current;
+                    open.push(OpenNode {
+                        estimated_cost: new_cost + heuristic(neighbor),
+                        index: index(neighbor),
+                    });
+                }
+            }
+        }
+
+        let mut cells = vec![closest];
+        while let Some(&last) = cells.last() {
+            if came_from[last] == std::usize::MAX {
+                break;
+            }
+            cells.push(came_from[last]);
+        }
+        cells.reverse();
+
+        //Only keep the cells where the path has to turn
+        let mut waypoints = Vec::new();
+        let mut anchor = start.coords.xy();
+        let mut n = 1;
+        while n < cells.len() {
+            let next = grid.center_of(cell(cells[n]));
+            if n + 1 < cells.len() {
+                let after = grid.center_of(cell(cells[n + 1]));
+                if grid.line_of_sight(anchor, after) {
+                    n += 1;
+                    continue;
+                }
+            }
+            waypoints.push(next);
+            anchor = next;
+            n += 1;
+        }
+        //The exact goal replaces the center of its cell, an unreachable goal is not part of the path
+        if closest == index(goal_cell) {
+            waypoints.pop();
+            waypoints.push(goal.coords.xy());
+        } else if waypoints.is_empty() {
+            waypoints.push(grid.center_of(cell(closest)));
+        }
+
+        waypoints
+            .iter()
+            .map(|w| Point3::new(w.x, w.y, heightmap_phy.z_linear(w.x, w.y)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Flat ground at 50 m on the left half, a lake 40 m deep on the right half
+    fn half_lake() -> HeightmapPhy {
+        let mut heightmap_phy = HeightmapPhy::new(64, 64);
+        for j in 0..64 {
+            for i in 32..64 {
+                heightmap_phy.texels[i + j * 64] = 0.0;
+            }
+        }
+        heightmap_phy
+    }
+
+    #[test]
+    fn reachable_goal_ends_at_goal() {
+        let heightmap_phy = half_lake();
+        let mut pathfinding = Pathfinding::new();
+        let goal = Point3::new(20.0, 50.0, 50.0);
+        let path = pathfinding.find_path(
+            &heightmap_phy,
+            &MovementClass::kbot(),
+            Point3::new(6.0, 6.0, 50.0),
+            goal,
+        );
+        let last = path.last().unwrap();
+        assert_eq!(last.coords.xy(), goal.coords.xy());
+    }
+
+    #[test]
+    fn unreachable_goal_ends_at_closest_reachable_cell() {
+        let heightmap_phy = half_lake();
+        let movement_class = MovementClass::kbot();
+        let mut pathfinding = Pathfinding::new();
+        let goal = Point3::new(56.0, 32.0, 0.0);
+        let path = pathfinding.find_path(
+            &heightmap_phy,
+            &movement_class,
+            Point3::new(6.0, 32.0, 50.0),
+            goal,
+        );
+        let last = *path.last().unwrap();
+        assert!(last.x < 32.0);
+
+        let grid = pathfinding.passability(&heightmap_phy, &movement_class);
+        let last_cell = grid.cell_of(last.coords.xy());
+        assert!(grid.is_passable(last_cell));
+        assert_eq!(grid.center_of(last_cell), last.coords.xy());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::botdef;
+use crate::frame::{Frame, Player};
+use crate::heightmap_phy::HeightmapPhy;
+use crate::mobile::KBot;
+use crate::utils::Id;
+use fnv::FnvHashMap;
+use na::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+///m, side of a cell of the visibility grids
+pub const CELL_SIZE: f32 = 8.0;
+///m, distance between two terrain samples along a line of sight
+const TERRAIN_STEP: f32 = 2.0;
+///m, height above the ground of what is seen in a cell
+const TARGET_HEIGHT: f32 = 1.0;
+
+///What a team can see of the map this frame
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TeamVisibility {
+    pub width: usize,
+    pub height: usize,
+    ///In line of sight of a completed kbot of the team
+    pub sight: Vec<bool>,
+    ///In range of a radar of the team
+    pub radar: Vec<bool>,
+    ///In range of a jammer of the team, hidden from enemy radars
+    pub jammer: Vec<bool>,
+}
+
+///Kbot detected by radar but not in sight, its type and id are unknown
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RadarContact {
+    ///Index of the contact in this view of the frame, unrelated to the kbot
+    pub id: usize,
+    ///Center of the visibility cell of the kbot, on the ground
+    pub position: Point3<f32>,
+    pub team: u8,
+}
+
+impl TeamVisibility {
+    pub fn new(width: usize, height: usize) -> Self {
+        TeamVisibility {
+            width,
+            height,
+            sight: vec![false; width * height],
+            radar: vec![false; width * height],
+            jammer: vec![false; width * height],
+        }
+    }
+
+    fn index_of(&self, x: f32, y: f32) -> usize {
+        let i = ((x / CELL_SIZE).max(0.0) as usize).min(self.width - 1);
+        let j = ((y / CELL_SIZE).max(0.0) as usize).min(self.height - 1);
+        i + j * self.width
+    }
+
+    pub fn sees(&self, position: &Point3<f32>) -> bool {
+        self.sight[self.index_of(position.x, position.y)]
+    }
+
+    pub fn detects(&self, position: &Point3<f32>) -> bool {
+        self.radar[self.index_of(position.x, position.y)]
+    }
+
+    pub fn jams(&self, position: &Point3<f32>) -> bool {
+        self.jammer[self.index_of(position.x, position.y)]
+    }
+
+    ///Indices of the cells whose center is within radius of position
+    fn cells_in_disc(&self, position: &Point3<f32>, radius: f32) -> Vec<usize> {
+        let mut indices = Vec::new();
+        let min_i = ((position.x - radius) / CELL_SIZE).floor().max(0.0) as usize;
+        let max_i = (((position.x + radius) / CELL_SIZE).ceil().max(0.0) as usize).min(self.width);
+        let min_j = ((position.y - radius) / CELL_SIZE).floor().max(0.0) as usize;
+        let max_j = (((position.y + radius) / CELL_SIZE).ceil().max(0.0) as usize).min(self.height);
+        for j in min_j..max_j {
+            for i in min_i..max_i {
+                let dx = (i as f32 + 0.5) * CELL_SIZE - position.x;
+                let dy = (j as f32 + 0.5) * CELL_SIZE - position.y;
+                if dx * dx + dy * dy <= radius * radius {
+                    indices.push(i + j * self.width);
+                }
+            }
+        }
+        indices
+    }
+
+    fn center_of(&self, index: usize) -> (f32, f32) {
+        (
+            ((index % self.width) as f32 + 0.5) * CELL_SIZE,
+            ((index / self.width) as f32 + 0.5) * CELL_SIZE,
+        )
+    }
+}
+
+///The terrain doesn't cut the segment
+fn line_of_sight(heightmap_phy: &HeightmapPhy, from: Point3<f32>, to: Point3<f32>) -> bool {
+    let step_count = ((to - from).xy().magnitude() / TERRAIN_STEP).ceil() as usize;
+    (1..step_count).all(|step| {
+        let p = from + (to - from) * (step as f32 / step_count as f32);
+        heightmap_phy.z_linear(p.x, p.y) <= p.z
+    })
+}
+
+///Sight, radar and jammer coverage of every team, sight is blocked by the terrain, radar and jammers are not
+pub fn compute(
+    kbots: &FnvHashMap<Id<KBot>, KBot>,
+    players: &FnvHashMap<Id<Player>, Player>,
+    bot_defs: &FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
+    heightmap_phy: &HeightmapPhy,
+) -> FnvHashMap<u8, TeamVisibility> {
+    let width = (heightmap_phy.width as f32 / CELL_SIZE).ceil() as usize;
+    let height = (heightmap_phy.height as f32 / CELL_SIZE).ceil() as usize;
+
+    let mut teams: FnvHashMap<u8, TeamVisibility> = players
+        .values()
+        .map(|player| (player.team, TeamVisibility::new(width, height)))
+        .collect();
+
+    for kbot in kbots.values() {
+        if kbot.con_completed < 1.0 {
+            continue;
+        }
+        let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+        let team = teams
+            .entry(kbot.team)
+            .or_insert_with(|| TeamVisibility::new(width, height));
+
+        let eye = kbot.position + Vector3::new(0.0, 0.0, botdef.radius);
+        for index in team.cells_in_disc(&kbot.position, botdef.sight_radius) {
+            if !team.sight[index] {
+                let (x, y) = team.center_of(index);
+                let target = Point3::new(x, y, heightmap_phy.z_linear(x, y) + TARGET_HEIGHT);
+                team.sight[index] = line_of_sight(heightmap_phy, eye, target);
+            }
+        }
+        //The cell of the kbot is always seen
+        let own_index = team.index_of(kbot.position.x, kbot.position.y);
+        team.sight[own_index] = true;
+
+        for index in team.cells_in_disc(&kbot.position, botdef.radar_radius) {
+            team.radar[index] = true;
+        }
+        for index in team.cells_in_disc(&kbot.position, botdef.jammer_radius) {
+            team.jammer[index] = true;
+        }
+    }
+    teams
+}
+
+///View of the frame for a team.
+///Enemy kbots in sight are kept, the ones only on radar and not jammed become radar_contacts,
+///the others and the cloaked ones are hidden.
+///Only the projectiles and features in sight, the players of the team and its visibility are kept.
+pub fn filter_frame(mut frame: Frame, team: u8) -> Frame {
+    let number = frame.number;
+    let visibility = &frame.visibility;
+    let team_visibility = visibility.get(&team);
+    let sees = |position: &Point3<f32>| {
+        team_visibility.map_or(false, |visibility| visibility.sees(position))
+    };
+    let detects = |kbot: &KBot| {
+        let jammed = visibility
+            .get(&kbot.team)
+            .map_or(false, |visibility| visibility.jams(&kbot.position));
+        !jammed && team_visibility.map_or(false, |visibility| visibility.detects(&kbot.position))
+    };
+
+    let heightmap_phy = &frame.heightmap_phy;
+    let mut radar_contacts: Vec<RadarContact> = frame
+        .kbots
+        .values()
+        .filter(|kbot| kbot.team != team && !kbot.cloaked && !sees(&kbot.position) && detects(kbot))
+        .map(|kbot| {
+            //Snapped to the cell, the exact position can't be recovered
+            let x = ((kbot.position.x / CELL_SIZE).floor() + 0.5) * CELL_SIZE;
+            let y = ((kbot.position.y / CELL_SIZE).floor() + 0.5) * CELL_SIZE;
+            let z = heightmap_phy
+                .as_ref()
+                .map_or(kbot.position.z, |heightmap_phy| {
+                    heightmap_phy.z_linear(x, y)
+                });
+            RadarContact {
+                id: 0,
+                position: Point3::new(x, y, z),
+                team: kbot.team,
+            }
+        })
+        .collect();
+    //Ordered by position so that ids and order tell nothing about the kbots
+    radar_contacts.sort_by(|a, b| {
+        (a.position.x, a.position.y, a.team)
+            .partial_cmp(&(b.position.x, b.position.y, b.team))
+            .unwrap()
+    });
+    for (id, contact) in radar_contacts.iter_mut().enumerate() {
+        contact.id = id;
+    }
+    frame.radar_contacts = radar_contacts;
+
+    frame
+        .kbots
+        .retain(|_, kbot| kbot.team == team || (!kbot.cloaked && sees(&kbot.position)));
+    frame
+        .kinematic_projectiles
+        .retain(|_, proj| sees(&proj.clone().position_at(number)));
+    frame
+        .kinematic_projectiles_birth
+        .retain(|proj| sees(&proj.clone().position_at(number)));
+    frame.features.retain(|_, feature| sees(&feature.position));
+    frame.players.retain(|_, player| player.team == team);
+    frame.visibility.retain(|other_team, _| *other_team == team);
+    frame
+}
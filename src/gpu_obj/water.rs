@@ -1,12 +1,14 @@
 use super::glsl_compiler;
 use crate::model;
 use wgpu::Device;
-use wgpu::{BindGroup, BindGroupLayout, RenderPass, TextureFormat, TextureView};
+use wgpu::{BindGroup, BindGroupLayout, CommandEncoder, RenderPass, TextureFormat, TextureView};
 
 pub struct WaterGpu {
     pipeline: wgpu::RenderPipeline,
     bind_group_layout: BindGroupLayout,
     bind_group: BindGroup,
+    ///Water level, m
+    uniform_buf: wgpu::Buffer,
 }
 
 impl WaterGpu {
@@ -19,6 +21,10 @@ impl WaterGpu {
     ) -> Self {
         log::trace!("WaterGpu new");
 
+        let uniform_buf = device
+            .create_buffer_mapped(1, wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST)
+            .fill_from_slice(&[0.0_f32]);
+
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             bindings: &[
                 wgpu::BindGroupLayoutBinding {
@@ -47,6 +53,11 @@ impl WaterGpu {
                     visibility: wgpu::ShaderStage::FRAGMENT,
                     ty: wgpu::BindingType::Sampler,
                 },
+                wgpu::BindGroupLayoutBinding {
+                    binding: 4,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                },
             ],
         });
 
@@ -55,6 +66,7 @@ impl WaterGpu {
             &bind_group_layout,
             last_pass_view,
             current_position_att,
+            &uniform_buf,
         );
 
         let pipeline =
@@ -64,9 +76,24 @@ impl WaterGpu {
             pipeline,
             bind_group,
             bind_group_layout,
+            uniform_buf,
         }
     }
 
+    pub fn update_uniform(&self, device: &Device, encoder: &mut CommandEncoder, water_level: f32) {
+        log::trace!("WaterGpu update_uniform");
+        let uniform_buf = device
+            .create_buffer_mapped(
+                1,
+                wgpu::BufferUsage::UNIFORM
+                    | wgpu::BufferUsage::COPY_DST
+                    | wgpu::BufferUsage::COPY_SRC,
+            )
+            .fill_from_slice(&[water_level]);
+
+        encoder.copy_buffer_to_buffer(&uniform_buf, 0, &self.uniform_buf, 0, 4);
+    }
+
     pub fn update_bind_group(
         &mut self,
         device: &Device,
@@ -78,6 +105,7 @@ impl WaterGpu {
             &self.bind_group_layout,
             last_pass_view,
             current_position_att,
+            &self.uniform_buf,
         );
     }
 
@@ -86,6 +114,7 @@ impl WaterGpu {
         bind_group_layout: &BindGroupLayout,
         last_pass_view: &TextureView,
         current_position_att: &TextureView,
+        uniform_buf: &wgpu::Buffer,
     ) -> BindGroup {
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
@@ -130,6 +159,13 @@ impl WaterGpu {
                     binding: 3,
                     resource: wgpu::BindingResource::Sampler(&sampler_pos_att),
                 },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: uniform_buf,
+                        range: 0..4,
+                    },
+                },
             ],
         })
     }
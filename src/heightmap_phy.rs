@@ -4,6 +4,13 @@ use serde::{Deserialize, Serialize};
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Data {
     pub metal_spots: Vec<MetalSpot>,
+    ///m
+    #[serde(default = "default_water_level")]
+    pub water_level: f32,
+}
+
+fn default_water_level() -> f32 {
+    40.0
 }
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct HeightmapPhy {
@@ -14,9 +21,10 @@ pub struct HeightmapPhy {
 }
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct MetalSpot {
-    metal_per_frame: f32,
-    x: usize,
-    y: usize,
+    ///metal/frame
+    pub metal_per_frame: f32,
+    pub x: usize,
+    pub y: usize,
 }
 
 trait HeightMapPhyUsize {
@@ -43,6 +51,7 @@ impl HeightmapPhy {
             height,
             data: Data {
                 metal_spots: Vec::new(),
+                water_level: default_water_level(),
             },
         }
     }
@@ -85,6 +94,11 @@ impl HeightmapPhy {
         z
     }
 
+    ///m, 0 on dry land
+    pub fn water_depth(&self, x: f32, y: f32) -> f32 {
+        (self.data.water_level - self.z_linear(x, y)).max(0.0)
+    }
+
     ///safe normal interpolation
     pub fn normal(&self, x: f32, y: f32) -> Vector3<f32> {
         let x = x.max(1.0).min(self.width as f32 - 2.0);
@@ -4,6 +4,7 @@ use crate::unit;
 use crate::utils;
 use na::{Matrix4, Point3, Vector2, Vector3};
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::path::{Path, PathBuf};
 use typename::TypeName;
 use utils::Id;
@@ -106,9 +107,112 @@ impl From<f32> for Angle {
 
 #[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
 pub enum Command {
-    None,
+    Move {
+        target: Point3<f32>,
+        ///Direction to turn to once arrived
+        facing: Option<Angle>,
+        ///m/frame, speed of the slowest unit of the formation to stay together
+        speed: Option<f32>,
+    },
     Build(Id<KBot>),
     Repair(Id<KBot>),
+    Attack(Id<KBot>),
+    Guard(Id<KBot>),
+    Reclaim(Reclaimable),
+}
+
+#[derive(Clone, Copy, TypeName, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Reclaimable {
+    Feature(Id<Feature>),
+    ///Only ennemy or unfinished kbots
+    KBot(Id<KBot>),
+}
+
+///Wreck left by a dead kbot, builders reclaim its metal
+#[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Feature {
+    pub id: Id<Feature>,
+    pub position: Point3<f32>,
+    pub dir: Vector3<f32>,
+    pub up: Vector3<f32>,
+    ///metal
+    pub metal: f32,
+    ///Botdef of the dead kbot, for the look of the wreck
+    pub botdef_id: Id<botdef::BotDef>,
+}
+
+///Units a factory builds, in order
+#[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FactoryQueue {
+    pub entries: VecDeque<FactoryQueueEntry>,
+    ///Each finished unit is queued again at the end
+    pub repeat: bool,
+}
+
+impl FactoryQueue {
+    pub fn new() -> Self {
+        FactoryQueue {
+            entries: VecDeque::new(),
+            repeat: false,
+        }
+    }
+
+    ///Add count units of botdef_id, a negative count removes some from the last entries
+    pub fn add(&mut self, botdef_id: Id<botdef::BotDef>, count: i32) {
+        if count > 0 {
+            match self.entries.back_mut() {
+                Some(last) if last.botdef_id == botdef_id => last.count += count,
+                _ => self
+                    .entries
+                    .push_back(FactoryQueueEntry { botdef_id, count }),
+            }
+        } else {
+            let mut to_remove = -count;
+            for entry in self.entries.iter_mut().rev() {
+                if entry.botdef_id == botdef_id {
+                    let removed = entry.count.min(to_remove);
+                    entry.count -= removed;
+                    to_remove -= removed;
+                }
+            }
+            self.entries.retain(|entry| entry.count > 0);
+        }
+    }
+
+    ///To call when the unit at the front of the queue is finished
+    pub fn pop_one(&mut self) {
+        if let Some(front) = self.entries.front_mut() {
+            let botdef_id = front.botdef_id;
+            front.count -= 1;
+            if front.count <= 0 {
+                self.entries.pop_front();
+            }
+            if self.repeat {
+                match self.entries.back_mut() {
+                    Some(last) if last.botdef_id == botdef_id => last.count += 1,
+                    _ => self.entries.push_back(FactoryQueueEntry {
+                        botdef_id,
+                        count: 1,
+                    }),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FactoryQueueEntry {
+    pub botdef_id: Id<botdef::BotDef>,
+    pub count: i32,
+}
+
+///When a kbot is allowed to shoot without an explicit attack command
+#[derive(Clone, Copy, TypeName, Debug, Serialize, Deserialize, PartialEq)]
+pub enum FireState {
+    HoldFire,
+    ///Only shoot at who attacked us or our ward
+    ReturnFire,
+    FireAtWill,
 }
 
 #[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
@@ -119,18 +223,34 @@ pub struct KBot {
     pub dir: Vector3<f32>,
     pub angle: Angle,
     pub angular_velocity: f32,
+    ///Direction to turn to once stopped
+    pub facing: Option<Angle>,
     pub up: Vector3<f32>,
     pub move_target: Option<Point3<f32>>,
-    pub current_command: Command,
+    ///Waypoints toward the destination of the current command, see frame_server::pathfinding
+    pub path: Vec<Point3<f32>>,
+    ///Destination the path was computed for
+    pub path_destination: Option<Point3<f32>>,
+    pub commands: VecDeque<Command>,
     pub life: i32,
     pub con_completed: f32,
     pub player_id: Id<Player>,
     pub team: u8,
     pub grounded: bool,
-    pub frame_last_shot: i32,
-    pub weapon0_dir: Vector3<f32>,
+    pub last_attacker: Option<Id<KBot>>,
+    pub frame_last_hit: i32,
+    pub weapons: Vec<Weapon>,
+    pub fire_state: FireState,
+    ///Ordered to cloak, see BotDef::cloak
+    pub cloak_wanted: bool,
+    ///Invisible to enemies during this frame
+    pub cloaked: bool,
+    pub factory_queue: FactoryQueue,
+    ///Kbot being built by this factory
+    pub factory_building: Option<Id<KBot>>,
+    ///Where the units out of this factory go
+    pub rally_point: Option<Point3<f32>>,
     pub wheel0_angle: f32,
-    pub reload_frame_count: i32,
     pub botdef_id: Id<botdef::BotDef>,
 }
 
@@ -143,13 +263,22 @@ impl KBot {
             team: 0,
             dir: Vector3::new(1.0, 0.0, 0.0),
             angle: Angle::new(0.0),
+            facing: None,
             up: Vector3::new(0.0, 0.0, 1.0),
             move_target: None,
-            current_command: Command::None,
+            path: Vec::new(),
+            path_destination: None,
+            commands: VecDeque::new(),
             id: utils::rand_id(),
-            frame_last_shot: 0,
-            reload_frame_count: 3,
-            weapon0_dir: Vector3::new(1.0, 0.0, 0.0),
+            last_attacker: None,
+            frame_last_hit: 0,
+            weapons: botdef.weapons.iter().map(|_| Weapon::new()).collect(),
+            fire_state: FireState::FireAtWill,
+            cloak_wanted: false,
+            cloaked: false,
+            factory_queue: FactoryQueue::new(),
+            factory_building: None,
+            rally_point: None,
             wheel0_angle: 0.0,
             life: botdef.max_life,
             con_completed: 1.0,
@@ -158,6 +287,48 @@ impl KBot {
             angular_velocity: 0.0,
         }
     }
+
+    ///Replace the pending commands, or append to them if queue is set
+    pub fn push_command(&mut self, command: Command, queue: bool) {
+        if !queue {
+            self.commands.clear();
+            self.move_target = None;
+        }
+        //The path of the previous command doesn't lead to this one
+        if self.commands.is_empty() {
+            self.path.clear();
+            self.path_destination = None;
+        }
+        self.commands.push_back(command);
+    }
+
+    ///Kbot that hit us during the last memory_frame_count frames
+    pub fn recent_attacker(&self, frame_count: i32, memory_frame_count: i32) -> Option<Id<KBot>> {
+        if frame_count - self.frame_last_hit <= memory_frame_count {
+            self.last_attacker
+        } else {
+            None
+        }
+    }
+}
+
+///State of one of the weapon slots of a kbot, see BotDef::weapons
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Weapon {
+    pub dir: Vector3<f32>,
+    pub frame_last_shot: i32,
+    ///Last kbot shot at, see TargetingPolicy::KeepTarget
+    pub target: Option<Id<KBot>>,
+}
+
+impl Weapon {
+    pub fn new() -> Self {
+        Weapon {
+            dir: Vector3::new(1.0, 0.0, 0.0),
+            frame_last_shot: 0,
+            target: None,
+        }
+    }
 }
 
 pub struct ClientKbot {
@@ -165,7 +336,7 @@ pub struct ClientKbot {
     pub dir: Vector3<f32>,
     pub up: Vector3<f32>,
 
-    pub weapon0_dir: Vector3<f32>,
+    pub weapon_dirs: Vec<Vector3<f32>>,
     pub wheel0_angle: f32,
 
     pub trans: Option<Matrix4<f32>>,
@@ -180,7 +351,7 @@ impl ClientKbot {
             position,
             dir: Vector3::new(1.0, 0.0, 0.0),
             up: Vector3::new(0.0, 0.0, 1.0),
-            weapon0_dir: Vector3::new(1.0, 0.0, 0.0),
+            weapon_dirs: Vec::new(),
             wheel0_angle: 0.0,
             trans: None,
             is_in_screen: false,
@@ -193,6 +364,9 @@ impl ClientKbot {
 #[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
 pub struct KinematicProjectile {
     pub id: Id<KinematicProjectile>,
+    pub from: Id<KBot>,
+    pub botdef_id: Id<botdef::BotDef>,
+    pub weapon_index: usize,
     pub birth_frame: i32,
     pub death_frame: i32,
     pub position_at_birth: Point3<f32>,
@@ -237,6 +411,28 @@ impl KinematicProjectile {
             *self.position_cache.last().unwrap()
         }
     }
+
+    ///Speed at birth needed to hit a target moving in straight line, None if unreachable.
+    ///
+    ///Follows the integration of position_at: after n frames the projectile is at
+    ///from + n * speed + accel * n(n+1)/2, we take the earliest frame where the needed speed
+    ///is within muzzle_speed.
+    pub fn launch_speed_to_intercept(
+        from: Point3<f32>,
+        target_position: Point3<f32>,
+        target_speed: Vector3<f32>,
+        muzzle_speed: f32,
+        accel_per_frame: Vector3<f32>,
+        max_frame_count: i32,
+    ) -> Option<Vector3<f32>> {
+        (1..=max_frame_count)
+            .map(|n| {
+                let n = n as f32;
+                let target_at_n = target_position + target_speed * n;
+                (target_at_n - from - accel_per_frame * n * (n + 1.0) / 2.0) / n
+            })
+            .find(|speed| speed.magnitude() <= muzzle_speed)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -10,6 +10,8 @@ use utils::Id;
 pub struct ModDef {
     pub units_id: Vec<Id<BotDef>>,
     pub con_map: FnvHashMap<Id<BotDef>, Vec<Id<BotDef>>>,
+    ///Damage class of the weapon -> armor class of the target -> damage multiplier
+    pub damage_multipliers: FnvHashMap<String, FnvHashMap<String, f32>>,
 }
 
 impl ModDef {
@@ -17,6 +19,16 @@ impl ModDef {
         Self {
             units_id: Vec::new(),
             con_map: FnvHashMap::default(),
+            damage_multipliers: FnvHashMap::default(),
         }
     }
+
+    ///1.0 when the table has no entry for this pair
+    pub fn damage_multiplier(&self, damage_class: &str, armor_class: &str) -> f32 {
+        self.damage_multipliers
+            .get(damage_class)
+            .and_then(|by_armor| by_armor.get(armor_class))
+            .copied()
+            .unwrap_or(1.0)
+    }
 }
@@ -25,7 +25,11 @@ void main() {
 
     vec3 color = vec3(1.0);
 
-    if (v_team< 0){
+    if (v_team < -1.5){
+        //Radar contact, its type is unknown
+        color = vec3(0.6,0.6,0.6);
+    }
+    else if (v_team< 0){
         //Unit is selected
         color = vec3(1.0);
     }
@@ -17,6 +17,10 @@ layout(set = 0, binding = 0) uniform Locals {
     vec2 hmap_size;
 };
 
+layout(set = 1, binding = 4) uniform WaterLocals {
+    float water_level;
+};
+
 void main() {
     float min = -0.00000;
     float max = 1.0-min;
@@ -30,7 +34,6 @@ void main() {
     v_TexCoord = tc;
 
     v_floor_lwall_fwall_rwall = gl_InstanceIndex;
-    float water_level = 40; 
     vec3 pos = vec3(0); 
  
     switch(v_floor_lwall_fwall_rwall){
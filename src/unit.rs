@@ -24,6 +24,9 @@ pub enum PlacedCollider {
 pub enum Joint {
     Fix,
     AimWeapon0,
+    AimWeapon1,
+    AimWeapon2,
+    AimWeapon3,
     Wheel0,
 }
 
@@ -31,11 +34,36 @@ impl Joint {
     pub fn next(&self) -> Self {
         match self {
             Joint::Fix => Joint::AimWeapon0,
-            Joint::AimWeapon0 => Joint::Wheel0,
+            Joint::AimWeapon0 => Joint::AimWeapon1,
+            Joint::AimWeapon1 => Joint::AimWeapon2,
+            Joint::AimWeapon2 => Joint::AimWeapon3,
+            Joint::AimWeapon3 => Joint::Wheel0,
             Joint::Wheel0 => Joint::Fix,
         }
     }
 
+    ///The AimWeaponN joint for a given N
+    pub fn aim_weapon(index: usize) -> Option<Self> {
+        match index {
+            0 => Some(Joint::AimWeapon0),
+            1 => Some(Joint::AimWeapon1),
+            2 => Some(Joint::AimWeapon2),
+            3 => Some(Joint::AimWeapon3),
+            _ => None,
+        }
+    }
+
+    ///N for an AimWeaponN joint
+    pub fn aim_weapon_index(&self) -> Option<usize> {
+        match self {
+            Joint::AimWeapon0 => Some(0),
+            Joint::AimWeapon1 => Some(1),
+            Joint::AimWeapon2 => Some(2),
+            Joint::AimWeapon3 => Some(3),
+            _ => None,
+        }
+    }
+
     pub fn replace_with_next(&mut self) {
         let next = self.next();
         std::mem::replace(self, next);